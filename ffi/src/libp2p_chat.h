@@ -5,9 +5,64 @@
 #include <stddef.h>
 #include <stdint.h>
 
+/**
+ * An instance can be shared between threads: once started, it runs in its own
+ * thread, and the other functions can be called from any thread meanwhile.
+ */
 typedef struct libp2p_chat libp2p_chat_t;
 typedef struct libp2p_chat_handle libp2p_chat_handle_t;
 
+/**
+ * Configuration for `libp2p_chat_new_with_config`, created with
+ * `libp2p_chat_config_new` and changed with the setters below.
+ */
+typedef struct libp2p_chat_config libp2p_chat_config_t;
+
+/**
+ * Error codes returned by the functions below; their values are stable.
+ * The message of the last error is available with `libp2p_chat_last_error`.
+ */
+typedef enum libp2p_chat_error {
+  LIBP2P_CHAT_OK = 0,
+  /** A required pointer is NULL. */
+  LIBP2P_CHAT_ERROR_NULL_POINTER = -1,
+  /** An argument is invalid, e.g. a peer id that can't be parsed. */
+  LIBP2P_CHAT_ERROR_INVALID_ARGUMENT = -2,
+  /** The given buffer is too small for the message. */
+  LIBP2P_CHAT_ERROR_BUFFER_TOO_SMALL = -3,
+  /** The message exceeds the maximum message size. */
+  LIBP2P_CHAT_ERROR_MESSAGE_TOO_LARGE = -4,
+  /** The message could not be published, e.g. the outbox is full. */
+  LIBP2P_CHAT_ERROR_PUBLISH = -5,
+  /** The file could not be sent. */
+  LIBP2P_CHAT_ERROR_TRANSFER = -6,
+  /** The instance could not be created or run, e.g. the port is in use. */
+  LIBP2P_CHAT_ERROR_NETWORK = -7,
+  /** The instance is already started. */
+  LIBP2P_CHAT_ERROR_ALREADY_STARTED = -8,
+  /** The instance has been stopped. */
+  LIBP2P_CHAT_ERROR_STOPPED = -9,
+  /** A panic was caught; the instance may be unusable afterwards. */
+  LIBP2P_CHAT_ERROR_PANIC = -10,
+  /** An I/O error, e.g. the readiness descriptor could not be created. */
+  LIBP2P_CHAT_ERROR_IO = -11,
+} libp2p_chat_error_t;
+
+/**
+ * @brief Returns the message of the last error on the calling thread.
+ * The string is owned by the library, and is valid until the next failing
+ * call on the same thread.
+ * @return const char* null-terminated message, or NULL if there is none
+ */
+extern const char *libp2p_chat_last_error(void);
+
+/**
+ * @brief Returns the code of the last error on the calling thread, e.g. after
+ * a function returned NULL.
+ * @return int one of `libp2p_chat_error_t`, or 0 if there is none
+ */
+extern int libp2p_chat_last_error_code(void);
+
 /**
  * @brief Enables logging for libp2p chat client.
  */
@@ -15,41 +70,128 @@ extern void libp2p_chat_enable_logs(void);
 
 /**
  * @brief Create a new libp2p instance
- * @return libp2p_chat_t* pointer to the libp2p instance
+ * @return libp2p_chat_t* pointer to the libp2p instance, or NULL on error
  */
 extern libp2p_chat_t *libp2p_chat_new(void);
 
+/**
+ * @brief Create a new libp2p instance with the given configuration
+ * @param config configuration, copied so it can be freed right after
+ * @return libp2p_chat_t* pointer to the libp2p instance, or NULL on error
+ */
+extern libp2p_chat_t *
+libp2p_chat_new_with_config(const libp2p_chat_config_t *config);
+
+/**
+ * @brief Create a configuration with the defaults, which are the same as
+ * `libp2p_chat_new`
+ * @return libp2p_chat_config_t* pointer to the configuration, or NULL on error
+ */
+extern libp2p_chat_config_t *libp2p_chat_config_new(void);
+
+/**
+ * @brief Free the configuration
+ * @param config pointer to the configuration
+ */
+extern void libp2p_chat_config_free(libp2p_chat_config_t *config);
+
+/**
+ * @brief Set the identity, so that the peer id is kept across restarts; a new
+ * one is generated otherwise.
+ * @param config pointer to the configuration
+ * @param key ed25519 secret key
+ * @param key_len length of `key`, must be 32
+ * @return 0 on success, or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_config_set_identity_key(libp2p_chat_config_t *config,
+                                               const uint8_t *key,
+                                               size_t key_len);
+
+/**
+ * @brief Set the topic to chat on; only instances on the same topic see each
+ * other's messages.
+ * @param config pointer to the configuration
+ * @param topic null-terminated topic name, not empty and shorter than
+ * `LIBP2P_CHAT_TOPIC_SIZE`
+ * @return 0 on success, or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_config_set_topic(libp2p_chat_config_t *config,
+                                        const char *topic);
+
+/**
+ * @brief Add an address to listen on, e.g. "/ip4/127.0.0.1/tcp/4001"; once
+ * any is added, the port given to `libp2p_chat_start` is ignored.
+ * @param config pointer to the configuration
+ * @param addr null-terminated multiaddress
+ * @return 0 on success, or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_config_add_listen_addr(libp2p_chat_config_t *config,
+                                              const char *addr);
+
+/**
+ * @brief Enable or disable the discovery of peers on the local network with
+ * mDNS, enabled by default.
+ * @param config pointer to the configuration
+ * @param enabled whether to use mDNS
+ * @return 0 on success, or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_config_set_mdns(libp2p_chat_config_t *config,
+                                       bool enabled);
+
+/**
+ * @brief Add a peer to dial once started, e.g.
+ * "/ip4/10.0.0.1/tcp/4001/p2p/12D3Koo...".
+ * @param config pointer to the configuration
+ * @param addr null-terminated multiaddress, ending with the peer id
+ * @return 0 on success, or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_config_add_bootstrap_peer(libp2p_chat_config_t *config,
+                                                 const char *addr);
+
 /**
  * @brief Free the libp2p instance
+ *
+ * Once this returns, the message callback is no longer called, even if the
+ * instance was still running, so its `user_data` can be freed.
  * @param ptr pointer to the libp2p instance
  */
 extern void libp2p_chat_free(libp2p_chat_t *ptr);
 
 /**
  * @brief Start listening on the given address
+ *
+ * The instance runs in its own thread. If it stops on its own, e.g. it can't
+ * listen on the port, other functions fail with `LIBP2P_CHAT_ERROR_STOPPED`
+ * until `libp2p_chat_stop` returns why.
+ *
  * @param ptr pointer to the libp2p instance
- * @param port port to listen on
- * @return libp2p_chat_handle_t* handle for the thread that runs `libp2p`
+ * @param port port to listen on, unless listen addresses are configured
+ * @return libp2p_chat_handle_t* handle for the thread that runs `libp2p`,
+ * or NULL on error, such as when the instance is already started, or has been
+ * stopped
  */
 extern libp2p_chat_handle_t *libp2p_chat_start(libp2p_chat_t *ptr,
                                                unsigned short port);
 
 /**
- * @brief Stop the libp2p instance
+ * @brief Stop the libp2p instance; messages received so far can still be
+ * received afterwards, but it cannot be started again.
  * @param ptr pointer to the libp2p instance
- * @param handle_ptr handle for the thread that runs `libp2p`
+ * @param handle_ptr handle for the thread that runs `libp2p`, consumed
+ * @return 0 on success, or a `libp2p_chat_error_t` if the instance failed
  */
-extern void libp2p_chat_stop(libp2p_chat_t *ptr,
-                             libp2p_chat_handle_t *handle_ptr);
+extern int libp2p_chat_stop(libp2p_chat_t *ptr,
+                            libp2p_chat_handle_t *handle_ptr);
 
 /**
  * @brief Publishes a message to all connected peers.
- * Will fail if there are no peers connected.
+ * If there are no peers connected yet, the message is kept in an outbox and
+ * published once a peer shows up, unless it expires first.
  *
  * @param ptr pointer to the libp2p instance
  * @param data raw bytes
  * @param data_len length of `data`
- * @return non-zero if error
+ * @return 0 on success, or a `libp2p_chat_error_t`
  */
 extern int libp2p_chat_publish(libp2p_chat_t *ptr, const void *data,
                                size_t data_len);
@@ -59,8 +201,191 @@ extern int libp2p_chat_publish(libp2p_chat_t *ptr, const void *data,
  * @param ptr pointer to the libp2p instance
  * @param buf buffer to store the message
  * @param buf_size size of the buffer
- * @return int number of bytes received, or -1 on error
+ * @return int number of bytes received, or a `libp2p_chat_error_t`; a message
+ * that does not fit in the buffer is consumed with
+ * `LIBP2P_CHAT_ERROR_BUFFER_TOO_SMALL`, and 0 is returned for both an empty
+ * queue and an empty message, see `libp2p_chat_receive_with_metadata`
  */
 extern int libp2p_chat_receive(libp2p_chat_t *ptr, void *buf, size_t buf_size);
 
+/** Size of `libp2p_chat_metadata_t::author`, which fits any peer id. */
+#define LIBP2P_CHAT_PEER_ID_SIZE 128
+/** Size of `libp2p_chat_metadata_t::topic`, which fits any allowed topic. */
+#define LIBP2P_CHAT_TOPIC_SIZE 256
+
+/**
+ * Metadata of a message, filled in by `libp2p_chat_receive_with_metadata`.
+ */
+typedef struct libp2p_chat_metadata {
+  /** Peer id of the author, null-terminated. */
+  char author[LIBP2P_CHAT_PEER_ID_SIZE];
+  /** Topic the message was published to, null-terminated. */
+  char topic[LIBP2P_CHAT_TOPIC_SIZE];
+  /** When the author published the message, in milliseconds since the Unix
+   * epoch. */
+  uint64_t timestamp;
+  /** Length of the message, in bytes. */
+  size_t data_len;
+} libp2p_chat_metadata_t;
+
+/**
+ * @brief Reports the length of the next message, without receiving it.
+ * @param ptr pointer to the libp2p instance
+ * @param data_len set to the length of the message if there is one, may be
+ * NULL
+ * @return int 1 if a message is waiting, 0 if the queue is empty, or a
+ * `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_peek(const libp2p_chat_t *ptr, size_t *data_len);
+
+/**
+ * @brief Receives a message along with its author, topic and timestamp.
+ * A message that does not fit in the buffer is left in the queue, and fails
+ * with `LIBP2P_CHAT_ERROR_BUFFER_TOO_SMALL`; use `libp2p_chat_peek` to size
+ * the buffer.
+ *
+ * @param ptr pointer to the libp2p instance
+ * @param buf buffer to store the message, may be NULL if `buf_size` is 0
+ * @param buf_size size of the buffer
+ * @param metadata filled in with the metadata of the message
+ * @return int 1 if a message is received (`metadata->data_len` may be 0), 0
+ * if the queue is empty, or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_receive_with_metadata(libp2p_chat_t *ptr, void *buf,
+                                             size_t buf_size,
+                                             libp2p_chat_metadata_t *metadata);
+
+/**
+ * @brief Called for each received message, see
+ * `libp2p_chat_set_message_callback`.
+ * @param user_data pointer given along with the callback
+ * @param data raw bytes of the message, not null-terminated
+ * @param data_len length of `data`
+ * @param author peer id of the author, as a null-terminated string
+ * @param timestamp when the author published the message, in milliseconds
+ * since the Unix epoch
+ */
+typedef void (*libp2p_chat_message_cb)(void *user_data, const uint8_t *data,
+                                       size_t data_len, const char *author,
+                                       uint64_t timestamp);
+
+/**
+ * @brief Calls `callback` for each received message, instead of storing them
+ * for `libp2p_chat_receive`.
+ *
+ * The callback is called from a thread of the library, one message at a time
+ * as they are received. `data` and `author` are only valid during
+ * the call, copy them to keep them. Other functions may be called from the
+ * callback, except `libp2p_chat_free`.
+ *
+ * Messages wait for the callback in a queue as large as the received queue;
+ * those that arrive while it is full are dropped, and counted by
+ * `libp2p_chat_received_dropped`.
+ *
+ * Passing NULL stores the messages again. Once this returns, the previous
+ * callback is no longer called, so its `user_data` can be freed. The same
+ * holds for the current callback once `libp2p_chat_free` returns.
+ *
+ * @param ptr pointer to the libp2p instance
+ * @param callback function to call, or NULL
+ * @param user_data passed to the callback as is, may be NULL
+ * @return 0 on success, or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_set_message_callback(libp2p_chat_t *ptr,
+                                            libp2p_chat_message_cb callback,
+                                            void *user_data);
+
+/**
+ * @brief Returns a file descriptor that is readable while received messages
+ * are waiting, to be added to an event loop (e.g. `epoll`, `poll`).
+ *
+ * Once it is readable, call `libp2p_chat_receive` until it returns 0; do not
+ * read from or close the descriptor itself. It stays the same for the
+ * instance, and is closed by `libp2p_chat_free`. Messages delivered to a
+ * callback don't make it readable.
+ *
+ * @code
+ * struct epoll_event event = {.events = EPOLLIN};
+ * epoll_ctl(epfd, EPOLL_CTL_ADD, libp2p_chat_received_fd(ptr), &event);
+ * @endcode
+ *
+ * @param ptr pointer to the libp2p instance
+ * @return int file descriptor (non-negative), or a `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_received_fd(libp2p_chat_t *ptr);
+
+/**
+ * @brief Counts the received messages that have been dropped, because they
+ * were not received with `libp2p_chat_receive` before the queue was full.
+ * @param ptr pointer to the libp2p instance
+ * @return uint64_t number of dropped messages, or 0 on error
+ */
+extern uint64_t libp2p_chat_received_dropped(const libp2p_chat_t *ptr);
+
+/**
+ * @brief Offers a file to a peer, which downloads it in chunks and verifies
+ * its hash. Sending the same file again resumes an interrupted transfer.
+ * The transfer begins once the instance is started with `libp2p_chat_start`.
+ *
+ * @param ptr pointer to the libp2p instance
+ * @param peer_id peer id of the receiver, as a null-terminated string
+ * @param path path of the file to send, as a null-terminated string
+ * @return int64_t transfer id (non-negative), or a `libp2p_chat_error_t`
+ */
+extern int64_t libp2p_chat_send_file(libp2p_chat_t *ptr, const char *peer_id,
+                                     const char *path);
+
+/** Size of `libp2p_chat_transfer_event_t::detail`, longer values are
+ * truncated. */
+#define LIBP2P_CHAT_TRANSFER_DETAIL_SIZE 1024
+
+/**
+ * Kind of a file transfer event.
+ */
+typedef enum libp2p_chat_transfer_kind {
+  /** Transfer has started, resuming from `bytes` if non-zero; `detail` is the
+   * file name. */
+  LIBP2P_CHAT_TRANSFER_STARTED = 0,
+  /** Transfer has progressed up to `bytes` out of `size`. */
+  LIBP2P_CHAT_TRANSFER_PROGRESS = 1,
+  /** Transfer has finished and the hash is verified; `detail` is the path of
+   * the file. */
+  LIBP2P_CHAT_TRANSFER_COMPLETED = 2,
+  /** Transfer has failed; `detail` is the reason. */
+  LIBP2P_CHAT_TRANSFER_FAILED = 3,
+} libp2p_chat_transfer_kind_t;
+
+/**
+ * A file transfer event, filled in by `libp2p_chat_next_transfer_event`.
+ */
+typedef struct libp2p_chat_transfer_event {
+  /** What happened to the transfer. */
+  libp2p_chat_transfer_kind_t kind;
+  /** Whether the file is received from the peer, rather than sent to it. */
+  bool incoming;
+  /** Transfer id, unique per peer & direction. */
+  uint64_t id;
+  /** Peer id of the other side, null-terminated. */
+  char peer_id[LIBP2P_CHAT_PEER_ID_SIZE];
+  /** Number of bytes transferred so far, 0 if unknown. */
+  uint64_t bytes;
+  /** Size of the file, 0 if unknown. */
+  uint64_t size;
+  /** File name, path or reason depending on `kind`, null-terminated. */
+  char detail[LIBP2P_CHAT_TRANSFER_DETAIL_SIZE];
+} libp2p_chat_transfer_event_t;
+
+/**
+ * @brief Pops the next file transfer event, such as progress or completion.
+ * Progress is coalesced per transfer, so polling now and then is enough to
+ * follow it.
+ *
+ * @param ptr pointer to the libp2p instance
+ * @param event filled in with the event
+ * @return int 1 if an event is popped, 0 if there are none, or a
+ * `libp2p_chat_error_t`
+ */
+extern int libp2p_chat_next_transfer_event(libp2p_chat_t *ptr,
+                                           libp2p_chat_transfer_event_t *event);
+
 #endif // LIBP2P_CHAT_H
\ No newline at end of file
@@ -1,22 +1,25 @@
 #include <signal.h>
-#include <stdbool.h>
 #include <stdio.h>
-#include <unistd.h>
 
 #include "libp2p_chat.h"
 
-static bool is_running;
-static char buf[256];
-
-/// Signal handler to stop the program gracefully,
-/// will simply reset `is_running`.
-static inline void signal_handler(int signal) {
-  (void)signal; // unused
-  is_running = false;
+/// Called from a thread of the library for each received message.
+static void on_message(void *user_data, const uint8_t *data, size_t data_len,
+                       const char *author, uint64_t timestamp) {
+  (void)user_data; // unused
+  // `data` is not null-terminated, and only valid during this call
+  printf("[%llu] %s: %.*s\n", (unsigned long long)timestamp, author,
+         (int)data_len, (const char *)data);
+  fflush(stdout);
 }
 
 int main(void) {
-  signal(SIGINT, &signal_handler);
+  // block SIGINT before the library starts its threads, which inherit the
+  // mask; so that it is only received by `sigwait` below
+  sigset_t signals;
+  sigemptyset(&signals);
+  sigaddset(&signals, SIGINT);
+  pthread_sigmask(SIG_BLOCK, &signals, NULL);
 
   // enables logging, respects `RUST_LOG` environment variable
   libp2p_chat_enable_logs();
@@ -24,35 +27,44 @@ int main(void) {
   // create a new libp2p instance
   libp2p_chat_t *libp2p_chat = libp2p_chat_new();
   if (!libp2p_chat) {
-    fprintf(stderr, "Failed to create libp2p chat instance\n");
+    fprintf(stderr, "Failed to create libp2p chat instance: %s\n",
+            libp2p_chat_last_error());
+    return 1;
+  }
+
+  // deliver messages as they arrive, rather than polling for them
+  if (libp2p_chat_set_message_callback(libp2p_chat, &on_message, NULL) !=
+      LIBP2P_CHAT_OK) {
+    fprintf(stderr, "Failed to set message callback: %s\n",
+            libp2p_chat_last_error());
+    libp2p_chat_free(libp2p_chat);
     return 1;
   }
-  libp2p_chat_handle_t *libp2p_chat_handle = libp2p_chat_start(libp2p_chat, 0);
 
-  // start listening
-  is_running = true;
-  while (is_running) {
-    int bytes = libp2p_chat_receive(libp2p_chat, buf, sizeof(buf));
-    if (bytes < 0) {
-      // something went wrong
-      fprintf(stderr, "Failed to receive messages\n");
-      break;
-    } else if (bytes == 0) {
-      // no messages received, wait a bit and then poll again
-      usleep(1000 * 250); // (ms)
-      continue;
-    } else {
-      // message received!
-      buf[bytes] = '\0'; // ensure null-termination
-      printf("%s\n", buf);
-    }
+  libp2p_chat_handle_t *libp2p_chat_handle = libp2p_chat_start(libp2p_chat, 0);
+  if (!libp2p_chat_handle) {
+    fprintf(stderr, "Failed to start libp2p chat instance: %s\n",
+            libp2p_chat_last_error());
+    libp2p_chat_free(libp2p_chat);
+    return 1;
   }
 
+  // listen until CTRL+C
+  int signal;
+  sigwait(&signals, &signal);
+
   // gracefully stop the libp2p instance
-  libp2p_chat_stop(libp2p_chat, libp2p_chat_handle);
+  if (libp2p_chat_stop(libp2p_chat, libp2p_chat_handle) != LIBP2P_CHAT_OK) {
+    fprintf(stderr, "Failed to stop: %s\n", libp2p_chat_last_error());
+  }
+
+  // messages that arrived faster than the callback handled them are dropped
+  uint64_t dropped = libp2p_chat_received_dropped(libp2p_chat);
+  if (dropped > 0) {
+    fprintf(stderr, "Dropped %llu messages\n", (unsigned long long)dropped);
+  }
 
   // free the memory
   libp2p_chat_free(libp2p_chat);
-  signal(SIGINT, SIG_DFL);
   return 0;
-}
\ No newline at end of file
+}
@@ -0,0 +1,735 @@
+//! Bandwidth accounting per peer and per protocol, with optional per-peer caps.
+//!
+//! The transport is wrapped so that every stream counts the bytes read from & written to it.
+//! Streams don't know which protocol they carry, so the protocol is taken from the
+//! multistream-select negotiation at the start of each stream: the listener side echoes the
+//! protocol it has accepted, which is what we look for.
+//!
+//! Only the payload of the streams is counted, the overhead of yamux, noise & TCP is not.
+//! The traffic of a peer is forgotten once its last connection closes, but not its caps,
+//! which are only forgotten once they are full again: reconnecting does not reset them.
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::PeerId;
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox};
+use libp2p::core::transport::{Boxed, Transport};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+
+/// Protocol of the streams whose negotiation could not be followed.
+const UNKNOWN_PROTOCOL: &str = "unknown";
+/// Header line of multistream-select, sent by both sides before the protocol.
+const MULTISTREAM_HEADER: &[u8] = b"/multistream/1.0.0\n";
+/// Sent by the listener to refuse a protocol.
+const MULTISTREAM_NA: &[u8] = b"na\n";
+/// Give up looking for the protocol after this many bytes.
+const MAX_NEGOTIATION_SIZE: usize = 1024;
+
+/// Configuration of the bandwidth accounting.
+#[derive(Debug, Clone)]
+pub struct BandwidthConfig {
+    /// Rates are averaged over this interval.
+    pub rate_interval: Duration,
+    /// Maximum bytes per second received from a single peer, over all its connections.
+    ///
+    /// `Some(0)` blocks all traffic from peers, once their connections are established.
+    pub max_peer_inbound: Option<u64>,
+    /// Maximum bytes per second sent to a single peer, over all its connections.
+    ///
+    /// `Some(0)` blocks all traffic to peers, once their connections are established.
+    pub max_peer_outbound: Option<u64>,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            rate_interval: Duration::from_secs(1),
+            max_peer_inbound: None,
+            max_peer_outbound: None,
+        }
+    }
+}
+
+/// Traffic with a peer over a protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrafficStats {
+    pub peer_id: PeerId,
+    /// Protocol of the streams, e.g. `/meshsub/1.1.0`, or `unknown`.
+    pub protocol: String,
+    /// Total bytes received.
+    pub inbound: u64,
+    /// Total bytes sent.
+    pub outbound: u64,
+    /// Bytes received per second, over the last [`BandwidthConfig::rate_interval`].
+    pub inbound_rate: f64,
+    /// Bytes sent per second, over the last [`BandwidthConfig::rate_interval`].
+    pub outbound_rate: f64,
+}
+
+/// Byte counters of a peer & protocol.
+#[derive(Debug, Default)]
+struct Counters {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+/// A token bucket shared by all streams of a peer, in one direction.
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: u64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Returns how many bytes can be transferred right now, up to `wanted`,
+    /// or how long to wait for some tokens.
+    fn available(&mut self, wanted: usize) -> Result<usize, Duration> {
+        let elapsed = self.updated.elapsed();
+        let refill = (elapsed.as_secs_f64() * self.rate as f64) as u64;
+        if refill > 0 {
+            // at most one second worth of bytes can be bursted
+            self.tokens = (self.tokens + refill).min(self.rate);
+            self.updated = Instant::now();
+        }
+
+        if self.rate == 0 {
+            // blocked, but check every now and then rather than never waking up
+            return Err(Duration::from_secs(1));
+        }
+        if self.tokens == 0 {
+            return Err(
+                Duration::from_secs_f64(1.0 / self.rate as f64).max(Duration::from_millis(1))
+            );
+        }
+        Ok(wanted.min(self.tokens as usize))
+    }
+
+    #[inline]
+    fn consume(&mut self, bytes: usize) {
+        self.tokens = self.tokens.saturating_sub(bytes as u64);
+    }
+
+    /// Whether the bucket has refilled, so a new one would be the same.
+    fn is_full(&mut self) -> bool {
+        // refills along the way, if it is not blocked
+        self.rate == 0 || self.available(0).is_ok() && self.tokens == self.rate
+    }
+}
+
+/// Caps of a peer, shared by all its connections and kept across reconnections.
+#[derive(Debug)]
+struct Limits {
+    inbound: Option<Mutex<Bucket>>,
+    outbound: Option<Mutex<Bucket>>,
+}
+
+impl Limits {
+    fn new(config: &BandwidthConfig) -> Self {
+        Self {
+            inbound: config.max_peer_inbound.map(|r| Mutex::new(Bucket::new(r))),
+            outbound: config.max_peer_outbound.map(|r| Mutex::new(Bucket::new(r))),
+        }
+    }
+
+    /// Whether forgetting the caps would not let the peer transfer more.
+    fn is_idle(&self) -> bool {
+        [&self.inbound, &self.outbound]
+            .into_iter()
+            .flatten()
+            .all(|bucket| bucket.lock().expect("should not be poisoned").is_full())
+    }
+}
+
+/// Everything a stream needs to account its traffic with a peer.
+#[derive(Debug)]
+struct PeerTraffic {
+    /// Number of open connections, only changed while the peers are locked.
+    connections: AtomicUsize,
+    protocols: Mutex<HashMap<String, Arc<Counters>>>,
+    limits: Arc<Limits>,
+}
+
+impl PeerTraffic {
+    fn counters(&self, protocol: &str) -> Arc<Counters> {
+        let mut protocols = self.protocols.lock().expect("should not be poisoned");
+        protocols.entry(protocol.to_string()).or_default().clone()
+    }
+}
+
+/// Totals of a peer & protocol at the last sample, and the rates since the one before.
+#[derive(Debug, Default)]
+struct Sample {
+    inbound: u64,
+    outbound: u64,
+    inbound_rate: f64,
+    outbound_rate: f64,
+}
+
+/// Traffic of the peers with open connections, and the caps of the peers that had some recently.
+#[derive(Debug, Default)]
+struct PeerMaps {
+    traffic: HashMap<PeerId, Arc<PeerTraffic>>,
+    limits: HashMap<PeerId, Arc<Limits>>,
+}
+
+type Peers = Arc<Mutex<PeerMaps>>;
+
+/// Returns the traffic of a peer, along with a new connection.
+fn connected(peers: &Peers, config: &BandwidthConfig, peer_id: PeerId) -> Arc<PeerTraffic> {
+    let mut peers = peers.lock().expect("should not be poisoned");
+    let PeerMaps { traffic, limits } = &mut *peers;
+    let traffic = traffic
+        .entry(peer_id)
+        .or_insert_with(|| {
+            let limits = limits
+                .entry(peer_id)
+                .or_insert_with(|| Arc::new(Limits::new(config)));
+            Arc::new(PeerTraffic {
+                connections: AtomicUsize::new(0),
+                protocols: Default::default(),
+                limits: limits.clone(),
+            })
+        })
+        .clone();
+    traffic.connections.fetch_add(1, Ordering::Relaxed);
+    traffic
+}
+
+/// Forgets the traffic of a peer along with its last connection.
+fn disconnected(peers: &Peers, peer_id: &PeerId, traffic: &PeerTraffic) {
+    let mut peers = peers.lock().expect("should not be poisoned");
+    if traffic.connections.fetch_sub(1, Ordering::Relaxed) == 1 {
+        peers.traffic.remove(peer_id);
+    }
+}
+
+/// Bandwidth of all peers, shared with the streams of their connections.
+pub(crate) struct Bandwidth {
+    config: BandwidthConfig,
+    peers: Peers,
+    samples: HashMap<(PeerId, String), Sample>,
+    sampled: Instant,
+}
+
+impl Bandwidth {
+    pub(crate) fn new(config: BandwidthConfig) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+            samples: Default::default(),
+            sampled: Instant::now(),
+        }
+    }
+
+    /// Wraps the transport so that the streams of its connections are accounted.
+    pub(crate) fn wrap<T>(&self, transport: T) -> Boxed<(PeerId, StreamMuxerBox)>
+    where
+        T: Transport<Output = (PeerId, StreamMuxerBox)> + Send + Unpin + 'static,
+        T::Error: Send + Sync + 'static,
+        T::Dial: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+    {
+        let config = self.config.clone();
+        let peers = self.peers.clone();
+        transport
+            .map(move |(peer_id, muxer), _| {
+                let traffic = connected(&peers, &config, peer_id);
+                (
+                    peer_id,
+                    StreamMuxerBox::new(Muxer {
+                        inner: muxer,
+                        peer_id,
+                        peers: peers.clone(),
+                        traffic,
+                    }),
+                )
+            })
+            .boxed()
+    }
+
+    /// Updates the rates, should be called every [`BandwidthConfig::rate_interval`].
+    ///
+    /// Also forgets the caps of the peers that are gone, once they are full again.
+    pub(crate) fn sample(&mut self) {
+        let elapsed = self.sampled.elapsed().as_secs_f64();
+        self.sampled = Instant::now();
+        if elapsed == 0.0 {
+            return;
+        }
+
+        {
+            let mut peers = self.peers.lock().expect("should not be poisoned");
+            let PeerMaps { traffic, limits } = &mut *peers;
+            limits.retain(|peer_id, limits| traffic.contains_key(peer_id) || !limits.is_idle());
+        }
+
+        // samples of the peers that are gone are dropped along the way
+        let mut samples = HashMap::new();
+        for (peer_id, protocol, inbound, outbound) in self.totals() {
+            let key = (peer_id, protocol);
+            let mut sample = self.samples.remove(&key).unwrap_or_default();
+            if inbound < sample.inbound || outbound < sample.outbound {
+                // the peer has reconnected since, and its totals started over
+                sample = Sample::default();
+            }
+            sample.inbound_rate = (inbound - sample.inbound) as f64 / elapsed;
+            sample.outbound_rate = (outbound - sample.outbound) as f64 / elapsed;
+            sample.inbound = inbound;
+            sample.outbound = outbound;
+            samples.insert(key, sample);
+        }
+        self.samples = samples;
+    }
+
+    /// Returns the traffic per peer & protocol, with the rates as of the last sample.
+    pub(crate) fn stats(&self) -> Vec<TrafficStats> {
+        self.totals()
+            .into_iter()
+            .map(|(peer_id, protocol, inbound, outbound)| {
+                let (inbound_rate, outbound_rate) = self
+                    .samples
+                    .get(&(peer_id, protocol.clone()))
+                    .map_or((0.0, 0.0), |sample| {
+                        (sample.inbound_rate, sample.outbound_rate)
+                    });
+                TrafficStats {
+                    peer_id,
+                    protocol,
+                    inbound,
+                    outbound,
+                    inbound_rate,
+                    outbound_rate,
+                }
+            })
+            .collect()
+    }
+
+    fn totals(&self) -> Vec<(PeerId, String, u64, u64)> {
+        let peers = self.peers.lock().expect("should not be poisoned");
+        peers
+            .traffic
+            .iter()
+            .flat_map(|(peer_id, traffic)| {
+                let protocols = traffic.protocols.lock().expect("should not be poisoned");
+                protocols
+                    .iter()
+                    .map(|(protocol, counters)| {
+                        (
+                            *peer_id,
+                            protocol.clone(),
+                            counters.inbound.load(Ordering::Relaxed),
+                            counters.outbound.load(Ordering::Relaxed),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+/// A stream muxer whose streams are accounted.
+struct Muxer {
+    inner: StreamMuxerBox,
+    peer_id: PeerId,
+    peers: Peers,
+    traffic: Arc<PeerTraffic>,
+}
+
+impl Drop for Muxer {
+    fn drop(&mut self) {
+        // streams left keep their own reference
+        disconnected(&self.peers, &self.peer_id, &self.traffic);
+    }
+}
+
+impl StreamMuxer for Muxer {
+    type Substream = Stream;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(Pin::new(&mut self.inner).poll_inbound(cx))?;
+        // we are the listener, so the protocol is in what we write
+        Poll::Ready(Ok(Stream::new(inner, self.traffic.clone(), false)))
+    }
+
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(Pin::new(&mut self.inner).poll_outbound(cx))?;
+        // we are the dialer, so the protocol is in what we read
+        Poll::Ready(Ok(Stream::new(inner, self.traffic.clone(), true)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
+
+/// An accounted stream.
+struct Stream {
+    inner: SubstreamBox,
+    traffic: Arc<PeerTraffic>,
+    /// Counters of the protocol, once it is known.
+    counters: Option<Arc<Counters>>,
+    /// Whether the protocol is found in the bytes we read, or in the ones we write.
+    protocol_inbound: bool,
+    /// Bytes of the negotiation seen so far, and the traffic until the protocol is known.
+    negotiation: Vec<u8>,
+    pending: (u64, u64),
+    /// Set while waiting for the bandwidth cap.
+    read_delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    write_delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Stream {
+    fn new(inner: SubstreamBox, traffic: Arc<PeerTraffic>, protocol_inbound: bool) -> Self {
+        Self {
+            inner,
+            traffic,
+            counters: None,
+            protocol_inbound,
+            negotiation: Vec::new(),
+            pending: (0, 0),
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Accounts the bytes transferred in one direction.
+    fn record(&mut self, inbound: bool, data: &[u8]) {
+        if self.counters.is_none() && inbound == self.protocol_inbound {
+            self.negotiation.extend_from_slice(data);
+            if let Some(protocol) = parse_protocol(&self.negotiation) {
+                self.identified(&protocol);
+            }
+        }
+
+        let bytes = data.len() as u64;
+        match (&self.counters, inbound) {
+            (Some(counters), true) => _ = counters.inbound.fetch_add(bytes, Ordering::Relaxed),
+            (Some(counters), false) => _ = counters.outbound.fetch_add(bytes, Ordering::Relaxed),
+            (None, true) => self.pending.0 += bytes,
+            (None, false) => self.pending.1 += bytes,
+        }
+    }
+
+    /// Moves the traffic so far to the counters of the protocol.
+    fn identified(&mut self, protocol: &str) {
+        let counters = self.traffic.counters(protocol);
+        counters
+            .inbound
+            .fetch_add(self.pending.0, Ordering::Relaxed);
+        counters
+            .outbound
+            .fetch_add(self.pending.1, Ordering::Relaxed);
+        self.pending = (0, 0);
+        self.negotiation = Vec::new();
+        self.counters = Some(counters);
+    }
+
+    /// Returns how many bytes can be transferred now, or `Pending` after scheduling a wake-up.
+    fn poll_limit(&mut self, cx: &mut Context<'_>, inbound: bool, wanted: usize) -> Poll<usize> {
+        let (limit, delay) = if inbound {
+            (&self.traffic.limits.inbound, &mut self.read_delay)
+        } else {
+            (&self.traffic.limits.outbound, &mut self.write_delay)
+        };
+        let Some(limit) = limit else {
+            return Poll::Ready(wanted);
+        };
+
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        let available = limit
+            .lock()
+            .expect("should not be poisoned")
+            .available(wanted);
+        match available {
+            Ok(available) => Poll::Ready(available),
+            Err(wait) => {
+                let mut sleep = Box::pin(tokio::time::sleep(wait));
+                // registers the waker, it can't be ready right away
+                let _ = sleep.as_mut().poll(cx);
+                *delay = Some(sleep);
+                Poll::Pending
+            }
+        }
+    }
+
+    #[inline]
+    fn consume(&self, inbound: bool, bytes: usize) {
+        let limit = if inbound {
+            &self.traffic.limits.inbound
+        } else {
+            &self.traffic.limits.outbound
+        };
+        if let Some(limit) = limit {
+            limit.lock().expect("should not be poisoned").consume(bytes);
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if self.counters.is_none() && self.pending != (0, 0) {
+            self.identified(UNKNOWN_PROTOCOL);
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let allowed = ready!(this.poll_limit(cx, true, buf.len()));
+        let read = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..allowed]))?;
+        this.consume(true, read);
+        this.record(true, &buf[..read]);
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let allowed = ready!(this.poll_limit(cx, false, buf.len()));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        this.consume(false, written);
+        this.record(false, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Finds the protocol accepted by the listener, in what the listener has sent so far.
+///
+/// Messages are length-prefixed (unsigned varint) lines; the listener sends the header first,
+/// then `na` for each refused protocol, and finally the accepted protocol.
+/// Returns `unknown` if the bytes don't look like a negotiation, and `None` if more are needed.
+fn parse_protocol(mut data: &[u8]) -> Option<String> {
+    if data.len() > MAX_NEGOTIATION_SIZE {
+        return Some(UNKNOWN_PROTOCOL.to_string());
+    }
+
+    loop {
+        // lines are short, so a 1 or 2 byte varint is enough
+        let (len, prefix) = match data {
+            [] => return None,
+            [first, ..] if first & 0x80 == 0 => (*first as usize, 1),
+            [_] => return None,
+            [first, second, ..] if second & 0x80 == 0 => {
+                ((*first & 0x7f) as usize | (*second as usize) << 7, 2)
+            }
+            _ => return Some(UNKNOWN_PROTOCOL.to_string()),
+        };
+        if data.len() < prefix + len {
+            return None;
+        }
+
+        let message = &data[prefix..prefix + len];
+        data = &data[prefix + len..];
+        if message == MULTISTREAM_HEADER || message == MULTISTREAM_NA {
+            continue;
+        }
+
+        return Some(match message.strip_suffix(b"\n") {
+            Some(protocol) if protocol.starts_with(b"/") => {
+                String::from_utf8_lossy(protocol).into_owned()
+            }
+            _ => UNKNOWN_PROTOCOL.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes messages as multistream-select does, with a varint length prefix.
+    fn negotiation(messages: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for message in messages {
+            let len = message.len();
+            if len < 0x80 {
+                data.push(len as u8);
+            } else {
+                data.extend_from_slice(&[(len & 0x7f) as u8 | 0x80, (len >> 7) as u8]);
+            }
+            data.extend_from_slice(message);
+        }
+        data
+    }
+
+    #[test]
+    fn parse_accepted_protocol() {
+        let data = negotiation(&[MULTISTREAM_HEADER, b"/meshsub/1.1.0\n"]);
+        assert_eq!(parse_protocol(&data).as_deref(), Some("/meshsub/1.1.0"));
+
+        // refused protocols are skipped
+        let data = negotiation(&[MULTISTREAM_HEADER, MULTISTREAM_NA, b"/ipfs/ping/1.0.0\n"]);
+        assert_eq!(parse_protocol(&data).as_deref(), Some("/ipfs/ping/1.0.0"));
+
+        // two-byte length prefix
+        let long = format!("/{}\n", "a".repeat(200));
+        let data = negotiation(&[MULTISTREAM_HEADER, long.as_bytes()]);
+        assert_eq!(parse_protocol(&data), Some(long.trim_end().to_string()));
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        let data = negotiation(&[MULTISTREAM_HEADER, b"/meshsub/1.1.0\n"]);
+        for len in [0, 1, MULTISTREAM_HEADER.len() + 1, data.len() - 1] {
+            assert_eq!(parse_protocol(&data[..len]), None, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn parse_unknown() {
+        let unknown = Some(UNKNOWN_PROTOCOL.to_string());
+        assert_eq!(parse_protocol(&negotiation(&[b"garbage\n"])), unknown);
+        assert_eq!(parse_protocol(&negotiation(&[b"/no-newline"])), unknown);
+        assert_eq!(parse_protocol(&[0xff, 0xff, 0x01]), unknown);
+        assert_eq!(parse_protocol(&vec![0; MAX_NEGOTIATION_SIZE + 1]), unknown);
+    }
+
+    #[test]
+    fn bucket_limits() {
+        let mut bucket = Bucket::new(100);
+        assert_eq!(bucket.available(1000), Ok(100));
+        bucket.consume(100);
+        assert!(bucket.available(1).is_err());
+
+        // a zero rate blocks without dividing by zero
+        let mut blocked = Bucket::new(0);
+        assert_eq!(blocked.available(1), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn reconnect_within_interval() {
+        let config = BandwidthConfig {
+            max_peer_inbound: Some(1000),
+            ..Default::default()
+        };
+        let mut bandwidth = Bandwidth::new(config.clone());
+        let peer_id = PeerId::random();
+
+        let traffic = connected(&bandwidth.peers, &config, peer_id);
+        let counters = traffic.counters("/meshsub/1.1.0");
+        counters.inbound.fetch_add(500, Ordering::Relaxed);
+        counters.outbound.fetch_add(500, Ordering::Relaxed);
+        traffic
+            .limits
+            .inbound
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .consume(1000);
+        std::thread::sleep(Duration::from_millis(1));
+        bandwidth.sample();
+        disconnected(&bandwidth.peers, &peer_id, &traffic);
+
+        // the totals start over, below the last sample
+        let traffic = connected(&bandwidth.peers, &config, peer_id);
+        traffic
+            .counters("/meshsub/1.1.0")
+            .inbound
+            .fetch_add(100, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(1));
+        bandwidth.sample();
+        let stats = bandwidth.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].inbound, stats[0].outbound), (100, 0));
+        assert!(stats[0].inbound_rate > 0.0 && stats[0].inbound_rate.is_finite());
+        assert_eq!(stats[0].outbound_rate, 0.0);
+
+        // nor is the cap reset
+        let mut bucket = traffic.limits.inbound.as_ref().unwrap().lock().unwrap();
+        assert!(bucket.available(1000).unwrap_or(0) < 1000);
+    }
+
+    #[test]
+    fn forget_idle_limits() {
+        let config = BandwidthConfig {
+            max_peer_inbound: Some(1_000_000),
+            ..Default::default()
+        };
+        let mut bandwidth = Bandwidth::new(config.clone());
+        let peer_id = PeerId::random();
+
+        let traffic = connected(&bandwidth.peers, &config, peer_id);
+        traffic
+            .limits
+            .inbound
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .consume(1_000_000);
+        disconnected(&bandwidth.peers, &peer_id, &traffic);
+        std::thread::sleep(Duration::from_millis(1));
+        bandwidth.sample();
+        assert!(
+            bandwidth
+                .peers
+                .lock()
+                .unwrap()
+                .limits
+                .contains_key(&peer_id)
+        );
+
+        // refilled after a second
+        traffic
+            .limits
+            .inbound
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .updated -= Duration::from_secs(1);
+        bandwidth.sample();
+        assert!(bandwidth.peers.lock().unwrap().limits.is_empty());
+        assert!(bandwidth.stats().is_empty());
+    }
+}
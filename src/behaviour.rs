@@ -1,4 +1,11 @@
-use libp2p::{gossipsub, identify, identity::Keypair, mdns, swarm::NetworkBehaviour};
+use crate::message::MESSAGE_OVERHEAD;
+use crate::transfer::{TRANSFER_PROTOCOL, TransferBehaviour};
+use crate::{ChatConfig, GossipsubConfig, PingConfig};
+use libp2p::allow_block_list::{self, AllowedPeers, BlockedPeers};
+use libp2p::swarm::{NetworkBehaviour, behaviour::toggle::Toggle};
+use libp2p::{connection_limits, memory_connection_limits};
+use libp2p::{gossipsub, identify, identity::Keypair, mdns, ping, request_response};
+use prometheus_client::registry::Registry;
 use std::{
     hash::{DefaultHasher, Hash, Hasher},
     time::Duration,
@@ -7,9 +14,18 @@ use std::{
 /// This macro will create a `ChatBehaviourEvent` type that swarm will emit in a stream.
 #[derive(NetworkBehaviour)]
 pub struct ChatBehaviour {
+    pub(crate) limits: connection_limits::Behaviour,
+    /// Only enabled with a memory limit, see [`ConnectionLimitsConfig`](crate::ConnectionLimitsConfig).
+    pub(crate) memory: Toggle<memory_connection_limits::Behaviour>,
+    pub(crate) blocked: allow_block_list::Behaviour<BlockedPeers>,
+    /// Only enabled in allow-only mode, see [`PeerListConfig`](crate::PeerListConfig).
+    pub(crate) allowed: Toggle<allow_block_list::Behaviour<AllowedPeers>>,
     pub(crate) gossipsub: gossipsub::Behaviour,
-    pub(crate) mdns: mdns::tokio::Behaviour,
+    /// Only enabled with mDNS discovery, see [`NetworkConfig`](crate::NetworkConfig).
+    pub(crate) mdns: Toggle<mdns::tokio::Behaviour>,
     pub(crate) identify: identify::Behaviour,
+    pub(crate) ping: ping::Behaviour,
+    pub(crate) transfer: TransferBehaviour,
 }
 
 /// A generic error type for the chat behaviour.
@@ -19,32 +35,69 @@ pub enum ChatBehaviourError {
     MDNS(std::io::Error),
     #[error("Could not create GossipSub config: {0}")]
     GossipsubConfig(gossipsub::ConfigBuilderError),
+    #[error(
+        "Maximum transmit size of {0} bytes leaves no room for the payload, it must exceed {MESSAGE_OVERHEAD} bytes"
+    )]
+    TransmitSizeTooSmall(usize),
     #[error("Could not create GossipSub behaviour: {0}")]
     Gossipsub(&'static str),
+    #[error("Could not enable GossipSub peer scoring: {0}")]
+    PeerScore(String),
 }
 
 impl ChatBehaviour {
     /// identify protocol string, looks like `chat/{major}.{minor}`
-    pub const PROTOCOL_VERSION: &str = concat!(
-        "chat/",
-        env!("CARGO_PKG_VERSION_MAJOR"),
-        ".",
-        env!("CARGO_PKG_VERSION_MINOR")
-    );
-
-    pub fn new(key: Keypair) -> Result<Self, ChatBehaviourError> {
+    ///
+    /// Peers with the same major version are compatible, see [`ProtocolVersion`](crate::ProtocolVersion).
+    /// It follows the wire format rather than the crate version: the major version is bumped
+    /// whenever older peers can't read our messages, as with the chunked envelope of `1.0`.
+    pub const PROTOCOL_VERSION: &str = "chat/1.0";
+
+    /// Creates the behaviour, registering the gossipsub metrics (including the mesh) in `registry`.
+    pub fn new(
+        key: Keypair,
+        config: &ChatConfig,
+        registry: &mut Registry,
+    ) -> Result<Self, ChatBehaviourError> {
         Ok(ChatBehaviour {
+            limits: connection_limits::Behaviour::new(config.limits.connection_limits()),
+            memory: config.limits.memory_limits().into(),
+            blocked: Default::default(),
+            allowed: config.peer_list.allow_only.then(Default::default).into(),
+            transfer: transfer_behaviour(),
             identify: identify_behaviour(&key),
-            mdns: mdns_behaviour(&key)?,
-            gossipsub: gossipsub_behaviour(key)?,
+            ping: ping_behaviour(&config.ping),
+            mdns: config
+                .network
+                .mdns
+                .then(|| mdns_behaviour(&key))
+                .transpose()?
+                .into(),
+            gossipsub: gossipsub_behaviour(
+                key,
+                &config.gossipsub,
+                &config.network.topic,
+                registry,
+            )?,
         })
     }
 }
 
 #[inline(always)]
-fn gossipsub_behaviour(keypair: Keypair) -> Result<gossipsub::Behaviour, ChatBehaviourError> {
-    use gossipsub::MessageAuthenticity;
+fn gossipsub_behaviour(
+    keypair: Keypair,
+    config: &GossipsubConfig,
+    topic: &str,
+    registry: &mut Registry,
+) -> Result<gossipsub::Behaviour, ChatBehaviourError> {
     use gossipsub::{Behaviour, ConfigBuilder, ValidationMode};
+    use gossipsub::{MessageAuthenticity, MetricsConfig};
+
+    if config.max_transmit_size <= MESSAGE_OVERHEAD {
+        return Err(ChatBehaviourError::TransmitSizeTooSmall(
+            config.max_transmit_size,
+        ));
+    }
 
     // make sure this is somehow unique per message, otherwise it will be gossip'ed infinitely
     let message_id_fn = |message: &gossipsub::Message| {
@@ -56,12 +109,65 @@ fn gossipsub_behaviour(keypair: Keypair) -> Result<gossipsub::Behaviour, ChatBeh
     let gossipsub_config = ConfigBuilder::default()
         .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
         .validation_mode(ValidationMode::Strict)
+        .validate_messages() // messages are forwarded only after the client validates them
+        .max_transmit_size(config.max_transmit_size)
         .message_id_fn(message_id_fn)
         .build()
         .map_err(ChatBehaviourError::GossipsubConfig)?;
 
-    Behaviour::new(MessageAuthenticity::Signed(keypair), gossipsub_config)
-        .map_err(ChatBehaviourError::Gossipsub)
+    let mut behaviour = Behaviour::new_with_metrics(
+        MessageAuthenticity::Signed(keypair),
+        gossipsub_config,
+        registry,
+        MetricsConfig::default(),
+    )
+    .map_err(ChatBehaviourError::Gossipsub)?;
+    behaviour
+        .with_peer_score(peer_score_params(topic), peer_score_thresholds(config))
+        .map_err(ChatBehaviourError::PeerScore)?;
+
+    Ok(behaviour)
+}
+
+/// Scoring parameters, with the chat topic as the only scored topic.
+#[inline(always)]
+fn peer_score_params(topic: &str) -> gossipsub::PeerScoreParams {
+    use gossipsub::{IdentTopic, PeerScoreParams, TopicScoreParams};
+
+    let topic_params = TopicScoreParams {
+        topic_weight: 1.0,
+        // reward peers for staying in the mesh, up to 10 minutes
+        time_in_mesh_weight: 0.01,
+        time_in_mesh_quantum: Duration::from_secs(1),
+        time_in_mesh_cap: 600.0,
+        // reward peers that are first to deliver a message
+        first_message_deliveries_weight: 1.0,
+        first_message_deliveries_decay: 0.9,
+        first_message_deliveries_cap: 50.0,
+        // chat is often quiet, so we don't penalize mesh peers for delivering too few messages
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        // messages rejected by the validator are penalized heavily, and remembered for a few minutes
+        invalid_message_deliveries_weight: -100.0,
+        invalid_message_deliveries_decay: 0.99,
+        ..Default::default()
+    };
+
+    let mut params = PeerScoreParams::default();
+    params
+        .topics
+        .insert(IdentTopic::new(topic).hash(), topic_params);
+    params
+}
+
+#[inline(always)]
+fn peer_score_thresholds(config: &GossipsubConfig) -> gossipsub::PeerScoreThresholds {
+    gossipsub::PeerScoreThresholds {
+        gossip_threshold: config.gossip_threshold,
+        publish_threshold: config.publish_threshold,
+        graylist_threshold: config.graylist_threshold,
+        ..Default::default()
+    }
 }
 
 #[inline(always)]
@@ -79,3 +185,23 @@ fn identify_behaviour(keypair: &Keypair) -> identify::Behaviour {
     let config = Config::new(ChatBehaviour::PROTOCOL_VERSION.into(), keypair.public());
     Behaviour::new(config)
 }
+
+#[inline(always)]
+fn ping_behaviour(config: &PingConfig) -> ping::Behaviour {
+    use ping::{Behaviour, Config};
+
+    let config = Config::new()
+        .with_interval(config.interval)
+        .with_timeout(config.timeout);
+    Behaviour::new(config)
+}
+
+#[inline(always)]
+fn transfer_behaviour() -> TransferBehaviour {
+    use request_response::{Config, ProtocolSupport};
+
+    TransferBehaviour::new(
+        [(TRANSFER_PROTOCOL, ProtocolSupport::Full)],
+        Config::default(),
+    )
+}
@@ -1,27 +1,84 @@
-use crate::{ChatBehaviour, ChatBehaviourEvent};
+use crate::bandwidth::Bandwidth;
+use crate::message::{Delivery, Envelope, Reassembler, Reassembly};
+use crate::metrics::{ChatMetrics, RejectReason};
+use crate::outbox::{Outbox, Pending};
+use crate::outgoing::Outgoing;
+use crate::peer_list::PeerLists;
+use crate::peers::{self, PeerTable};
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::transfer::{FileRequest, FileResponse, Transfers};
+use crate::worker::{Job, Outcome, Processed, SharedValidator, WorkerPool};
+use crate::{AcceptAll, MessageValidator, TransferEvent, TransferId, ValidationResult};
+use crate::{ChatBehaviour, ChatBehaviourEvent, ChatCommand, ChatConfig, ChatHandle, ChatMessage};
+use crate::{ChatSender, IncompatiblePeer, PeerInfo, ProtocolVersion};
+use crate::{LatencyStats, ReceivedQueue, ShutdownReport, TrafficStats, WorkerStats};
 use futures::StreamExt;
+use libp2p::core::{Transport, muxing::StreamMuxerBox, upgrade::Version};
 use libp2p::swarm::SwarmEvent;
-use libp2p::{PeerId, TransportError, gossipsub, identify, mdns, swarm};
+use libp2p::{PeerId, TransportError, gossipsub, identify, mdns, ping, request_response, swarm};
 use libp2p::{noise, tcp, yamux};
-use std::collections::VecDeque;
+use prometheus_client::registry::Registry;
+use std::collections::HashMap;
 use std::io;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
+/// Time given to the connection handlers to send their queued messages, before closing.
+const FLUSH_DELAY: Duration = Duration::from_millis(250);
+
 /// The main client struct that handles the chat functionality.
 ///
 /// - Shall be started with [`Self::run`] and will listen for incoming messages.
 /// - All received messages will be stored in the [`Self::received`] queue.
+/// - Can be controlled from other tasks with a [`ChatHandle`], see [`Self::handle`].
 /// - Can be stopped gracefully with [`Self::cancel`].
 pub struct ChatClient {
     /// The underlying [`swarm`] instance
     swarm: swarm::Swarm<ChatBehaviour>,
     /// Cancellation token to stop the client.
     cancellation: CancellationToken,
-    /// Message queue to store the messages received.
-    pub received: VecDeque<(PeerId, String)>,
-    /// Channel to send messages to this client.
-    sender_channel: mpsc::UnboundedReceiver<String>,
+    /// Message queue to store the messages received, bounded as per [`ChatConfig::received`].
+    pub received: ReceivedQueue,
+    /// Latencies of the received messages.
+    latencies: LatencyStats,
+    /// Channel of messages to publish, from [`ChatSender`]s.
+    sender_channel: mpsc::Receiver<Outgoing>,
+    /// Channel to receive commands from [`ChatHandle`]s.
+    command_channel: mpsc::UnboundedReceiver<ChatCommand>,
+    /// Sender side of [`Self::command_channel`], cloned into each [`ChatHandle`].
+    command_sender: mpsc::UnboundedSender<ChatCommand>,
+    /// Ongoing file transfers.
+    transfers: Transfers,
+    /// Chunks of large messages waiting to be reassembled.
+    reassembler: Reassembler,
+    /// Allowed & blocked peers.
+    peer_lists: PeerLists,
+    /// Peers we have come across, and what we know about them.
+    peers: PeerTable,
+    /// Peers that have been disconnected due to their protocol version.
+    incompatible_peers: HashMap<PeerId, IncompatiblePeer>,
+    /// Limits the rate of received messages per author.
+    rate_limiter: RateLimiter,
+    /// Messages waiting for peers to be published to.
+    outbox: Outbox,
+    /// Decides which received messages are accepted, shared with the workers.
+    validator: SharedValidator,
+    /// Workers that decompress & validate the received messages.
+    workers: WorkerPool,
+    /// Messages processed by the workers.
+    worker_results: mpsc::UnboundedReceiver<Processed>,
+    /// Prometheus metrics.
+    metrics: ChatMetrics,
+    /// Traffic per peer & protocol.
+    bandwidth: Bandwidth,
+    /// Topic to chat on, see [`NetworkConfig::topic`](crate::NetworkConfig::topic).
+    topic: gossipsub::IdentTopic,
+    /// Client configuration.
+    config: ChatConfig,
 }
 
 /// A generic error type for the chat client.
@@ -33,73 +90,500 @@ pub enum ChatClientError {
     ListenError(TransportError<io::Error>),
     #[error("Could not publish: {0}")]
     PublishError(gossipsub::PublishError),
+    #[error("Message of {0} bytes exceeds the maximum message size")]
+    MessageTooLarge(usize),
+    #[error("Could not transfer file: {0}")]
+    TransferError(io::Error),
+    #[error("Could not save peer lists: {0}")]
+    PeerListError(io::Error),
+    #[error("Could not serve metrics: {0}")]
+    MetricsError(io::Error),
+    #[error("Could not create the readiness descriptor: {0}")]
+    ReadinessError(io::Error),
+    #[error("Message expired in the outbox before it could be published")]
+    OutboxExpired,
+    #[error("Client is not running")]
+    ClientStopped,
 }
 
 impl ChatClient {
-    /// Gosipsub topic name for chatting.
+    /// Default gossipsub topic name for chatting, see [`NetworkConfig::topic`](crate::NetworkConfig::topic).
     pub const CHAT_TOPIC: &'static str = "rustconnect";
 
     /// Creates a new client instance listening on `0.0.0.0:{port}`.
     ///
     /// Use `0` to let the OS assign a port.
     ///
-    /// Returns a channel to send messages to the client.
-    pub fn new(
+    /// Returns a sender to publish messages through the client, see [`ChatSender`].
+    pub fn new(cancellation: CancellationToken) -> eyre::Result<(Self, ChatSender)> {
+        Self::with_config(ChatConfig::default(), cancellation)
+    }
+
+    /// Creates a new client instance with the given configuration, see [`Self::new`].
+    pub fn with_config(
+        config: ChatConfig,
         cancellation: CancellationToken,
-    ) -> eyre::Result<(Self, mpsc::UnboundedSender<String>)> {
-        // here we generate a new identity for the client, but we could take it from outside too
-        let keypair = libp2p::identity::Keypair::generate_ed25519();
+    ) -> eyre::Result<(Self, ChatSender)> {
+        if config.limits.raise_fd_limit {
+            crate::limits::raise_fd_limit();
+        }
+
+        // generate a new identity for the client, unless one is given
+        let keypair = config
+            .network
+            .keypair
+            .clone()
+            .unwrap_or_else(libp2p::identity::Keypair::generate_ed25519);
+        let topic = gossipsub::IdentTopic::new(&config.network.topic);
 
-        let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        let mut registry = Registry::default();
+        let bandwidth = Bandwidth::new(config.bandwidth.clone());
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
             .with_tokio()
-            .with_tcp(
-                tcp::Config::default(),
-                noise::Config::new, // uses existing keypair for Noise protocol
-                yamux::Config::default,
-            )?
-            .with_behaviour(|key| Ok(ChatBehaviour::new(key.clone()).unwrap()))
-            .unwrap()
+            // same as `with_tcp`, with the traffic of each connection accounted
+            .with_other_transport(|key| {
+                let transport = tcp::tokio::Transport::new(tcp::Config::default())
+                    .upgrade(Version::V1Lazy)
+                    .authenticate(noise::Config::new(key)?) // uses existing keypair for Noise protocol
+                    .multiplex(yamux::Config::default())
+                    .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(bandwidth.wrap(transport))
+            })?
+            .with_behaviour(|key| {
+                // gossipsub metrics go along the ones of libp2p, as `libp2p_gossipsub_*`
+                let registry = registry
+                    .sub_registry_with_prefix("libp2p")
+                    .sub_registry_with_prefix("gossipsub");
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(ChatBehaviour::new(
+                    key.clone(),
+                    &config,
+                    registry,
+                )?)
+            })?
+            .with_swarm_config(|swarm_config| {
+                // idle connections are kept open, failed pings close them instead
+                if config.ping.keep_alive {
+                    swarm_config.with_idle_connection_timeout(Duration::MAX)
+                } else {
+                    swarm_config
+                }
+            })
             .build();
 
-        let (sender, receiver) = mpsc::unbounded_channel();
+        // enforce the persisted peer lists at the connection level
+        let peer_lists = PeerLists::load(&config.peer_list)?;
+        let outbox = Outbox::load(&config.outbox)?;
+        let behaviour = swarm.behaviour_mut();
+        for peer_id in &peer_lists.blocked {
+            behaviour.blocked.block_peer(*peer_id);
+        }
+        if let Some(allowed) = behaviour.allowed.as_mut() {
+            for peer_id in &peer_lists.allowed {
+                allowed.allow_peer(*peer_id);
+            }
+        }
+
+        let metrics = ChatMetrics::new(registry);
+        let validator: SharedValidator = Arc::new(RwLock::new(Arc::new(AcceptAll)));
+        let (results_sender, worker_results) = mpsc::unbounded_channel();
+        let workers = WorkerPool::new(
+            &config.workers,
+            validator.clone(),
+            config.gossipsub.max_message_size,
+            results_sender,
+            metrics.worker_queue_depth.clone(),
+            metrics.worker_dropped.clone(),
+        )?;
+
+        let (sender, receiver) = ChatSender::channel(&config.outgoing);
+        let (command_sender, command_channel) = mpsc::unbounded_channel();
         Ok((
             Self {
                 swarm,
                 cancellation,
-                received: Default::default(),
+                received: ReceivedQueue::new(&config.received, metrics.received_dropped.clone()),
+                latencies: Default::default(),
                 // the "receiver" of this channel will be the channel used by "sender"
                 sender_channel: receiver,
+                command_channel,
+                command_sender,
+                transfers: Transfers::new(config.transfer.clone()),
+                reassembler: Reassembler::new(&config.gossipsub),
+                peer_lists,
+                peers: Default::default(),
+                incompatible_peers: Default::default(),
+                rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+                outbox,
+                validator,
+                workers,
+                worker_results,
+                metrics,
+                bandwidth,
+                topic,
+                config,
             },
             sender,
         ))
     }
 
     /// Publish a message to the chat topic.
-    pub fn publish(&mut self, message: impl AsRef<[u8]>) -> Result<(), ChatClientError> {
-        let topic = gossipsub::IdentTopic::new(Self::CHAT_TOPIC);
+    ///
+    /// Messages that don't fit in a single gossipsub message are split into chunks,
+    /// each published (and signed) separately, and reassembled by the receivers.
+    /// Chunks are only published once there are peers for all of them, but a chunk may still fail
+    /// after the previous ones went out, e.g. when the queues of the peers are full; receivers then
+    /// discard the partial message after [`GossipsubConfig::chunk_timeout`](crate::GossipsubConfig::chunk_timeout).
+    ///
+    /// Returns the id of the (first) gossipsub message. If there are no peers to publish to yet,
+    /// the message is kept in the outbox instead (see [`ChatConfig::outbox`]), and `None` is returned.
+    pub fn publish(
+        &mut self,
+        message: impl AsRef<[u8]>,
+    ) -> Result<Option<gossipsub::MessageId>, ChatClientError> {
+        let message = message.as_ref();
 
-        // append timestamp to message
-        let nanos = std::time::SystemTime::now()
+        // timestamp is shared by all chunks of the message
+        let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
-            .as_nanos();
+            .as_nanos() as u64;
 
-        let mut data = Vec::new();
-        data.extend_from_slice(&nanos.to_ne_bytes());
-        data.extend_from_slice(message.as_ref());
+        // messages in the outbox go first, so that the order is kept
+        self.flush_outbox();
+        let result = if self.outbox.is_empty() {
+            self.publish_at(timestamp, message)
+        } else {
+            Err(ChatClientError::PublishError(
+                gossipsub::PublishError::InsufficientPeers,
+            ))
+        };
 
-        self.swarm
-            .behaviour_mut()
+        match result {
+            Ok(message_id) => Ok(Some(message_id)),
+            Err(ChatClientError::PublishError(gossipsub::PublishError::InsufficientPeers))
+                if self.outbox.has_room() =>
+            {
+                log::info!("No peers to publish to yet, keeping the message in the outbox");
+                self.outbox.push(Pending {
+                    timestamp,
+                    data: message.to_vec(),
+                    ack: None,
+                });
+                if let Err(err) = self.outbox.save() {
+                    log::warn!("Could not save the outbox: {err}");
+                }
+                Ok(None)
+            }
+            Err(err) => {
+                self.metrics.publish_error();
+                Err(err)
+            }
+        }
+    }
+
+    /// Publishes a message with the given timestamp, see [`Self::publish`].
+    fn publish_at(
+        &mut self,
+        timestamp: u64,
+        message: &[u8],
+    ) -> Result<gossipsub::MessageId, ChatClientError> {
+        let config = &self.config.gossipsub;
+        if message.len() > config.max_message_size {
+            return Err(ChatClientError::MessageTooLarge(message.len()));
+        }
+
+        let topic = self.topic.clone();
+
+        // compress large payloads, unless it doesn't make them any smaller
+        let mut compression = config
+            .compression
+            .filter(|_| message.len() >= config.compression_threshold);
+        let compressed = match compression.map(|c| c.compress(message)) {
+            Some(Ok(compressed)) if compressed.len() < message.len() => compressed,
+            Some(Err(err)) => {
+                log::warn!("Could not compress message, publishing as is: {err}");
+                compression = None;
+                message.to_vec()
+            }
+            _ => {
+                compression = None;
+                message.to_vec()
+            }
+        };
+
+        let chunk_size = Envelope::max_chunk_size(config.max_transmit_size);
+        let envelopes = Envelope::split(timestamp, compression, &compressed, chunk_size)
+            .ok_or(ChatClientError::MessageTooLarge(message.len()))?;
+
+        // the first chunks would be left incomplete if the later ones can't be published either
+        let topic_hash = topic.hash();
+        let has_peers = self
+            .swarm
+            .behaviour()
             .gossipsub
-            .publish(topic, data)
-            .map_err(ChatClientError::PublishError)?;
+            .all_peers()
+            .any(|(_, topics)| topics.contains(&&topic_hash));
+        if envelopes.len() > 1 && !has_peers {
+            return Err(ChatClientError::PublishError(
+                gossipsub::PublishError::InsufficientPeers,
+            ));
+        }
 
-        Ok(())
+        let mut message_id = None;
+        for envelope in envelopes {
+            let id = self
+                .swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(topic.clone(), envelope.encode())
+                .map_err(ChatClientError::PublishError)?;
+            message_id.get_or_insert(id);
+        }
+
+        self.metrics.published();
+        Ok(message_id.expect("there is at least one chunk"))
+    }
+
+    /// Publishes the messages in the outbox, until there are no peers to publish to.
+    ///
+    /// Expired messages are dropped beforehand.
+    fn flush_outbox(&mut self) {
+        if self.outbox.is_empty() {
+            return;
+        }
+
+        for expired in self.outbox.expire() {
+            log::warn!("Message expired in the outbox");
+            self.metrics.publish_error();
+            if let Some(ack) = expired.ack {
+                let _ = ack.send(Err(ChatClientError::OutboxExpired));
+            }
+        }
+
+        while let Some(pending) = self.outbox.front() {
+            let (timestamp, data) = (pending.timestamp, pending.data.clone());
+            let result = self.publish_at(timestamp, &data);
+            if let Err(ChatClientError::PublishError(gossipsub::PublishError::InsufficientPeers)) =
+                result
+            {
+                break;
+            }
+
+            let pending = self.outbox.pop_front().expect("should not be empty");
+            if let Err(e) = &result {
+                log::error!("Error while publishing from the outbox: {e}");
+                self.metrics.publish_error();
+            }
+            if let Some(ack) = pending.ack {
+                let _ = ack.send(result);
+            }
+        }
+
+        if let Err(err) = self.outbox.save() {
+            log::warn!("Could not save the outbox: {err}");
+        }
+    }
+
+    /// Sets the validator that decides whether received messages are accepted.
+    ///
+    /// Only accepted messages are stored in [`Self::received`] and forwarded to other peers;
+    /// rejected ones also lower the score of the peer that sent them.
+    pub fn set_validator(&mut self, validator: impl MessageValidator) {
+        *self
+            .validator
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(validator);
+    }
+
+    /// Forwards the received messages to the given channel instead of storing them in [`Self::received`],
+    /// or stores them again with `None`.
+    ///
+    /// Messages that don't fit in the channel are dropped, and counted by [`ReceivedQueue::dropped`];
+    /// once the receiver is dropped, messages are stored again.
+    pub fn forward_received(&mut self, forward: Option<mpsc::Sender<ChatMessage>>) {
+        self.received.set_forward(forward);
+    }
+
+    /// Returns the peer id of this client.
+    #[inline]
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    /// Returns a handle to send commands to this client while it is running.
+    pub fn handle(&self) -> ChatHandle {
+        ChatHandle {
+            commands: self.command_sender.clone(),
+        }
+    }
+
+    /// Offers the file at `path` to the given peer, and returns the transfer id.
+    ///
+    /// The peer downloads the file in chunks and verifies its hash at the end.
+    /// If the transfer is interrupted, sending the same file again resumes it.
+    pub fn send_file(
+        &mut self,
+        peer_id: PeerId,
+        path: impl AsRef<Path>,
+    ) -> Result<TransferId, ChatClientError> {
+        self.transfers
+            .offer(
+                &mut self.swarm.behaviour_mut().transfer,
+                peer_id,
+                path.as_ref(),
+            )
+            .map_err(ChatClientError::TransferError)
+    }
+
+    /// Blocks all connections with the given peer, and closes the existing ones.
+    ///
+    /// The peer is removed from the allow list, and the lists are saved to their file.
+    pub fn block_peer(&mut self, peer_id: PeerId) -> Result<(), ChatClientError> {
+        log::info!("Blocking peer {peer_id}");
+        self.peer_lists.block(peer_id);
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.blocked.block_peer(peer_id);
+        if let Some(allowed) = behaviour.allowed.as_mut() {
+            allowed.disallow_peer(peer_id);
+        }
+
+        self.peer_lists
+            .save()
+            .map_err(ChatClientError::PeerListError)
+    }
+
+    /// Unblocks the given peer, and adds it to the allow list.
+    ///
+    /// In allow-only mode, only the peers in the allow list can connect.
+    /// The lists are saved to their file.
+    pub fn allow_peer(&mut self, peer_id: PeerId) -> Result<(), ChatClientError> {
+        log::info!("Allowing peer {peer_id}");
+        self.peer_lists.allow(peer_id);
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.blocked.unblock_peer(peer_id);
+        if let Some(allowed) = behaviour.allowed.as_mut() {
+            allowed.allow_peer(peer_id);
+        }
+
+        self.peer_lists
+            .save()
+            .map_err(ChatClientError::PeerListError)
+    }
+
+    /// Returns the peers in the allow list.
+    pub fn allowed_peers(&self) -> Vec<PeerId> {
+        self.peer_lists.allowed.iter().copied().collect()
+    }
+
+    /// Returns the peers in the block list.
+    pub fn blocked_peers(&self) -> Vec<PeerId> {
+        self.peer_lists.blocked.iter().copied().collect()
+    }
+
+    /// Returns what we know about the given peer, if we have come across it.
+    pub fn peer(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.peers
+            .get(peer_id)
+            .map(|info| self.with_mesh(peer_id, info))
+    }
+
+    /// Returns all peers we have come across, including those disconnected in the last 10 minutes.
+    pub fn peers(&self) -> Vec<(PeerId, PeerInfo)> {
+        self.peers
+            .iter()
+            .map(|(peer_id, info)| (*peer_id, self.with_mesh(peer_id, info)))
+            .collect()
+    }
+
+    /// Returns the protocol version negotiated with the given peer, if it is compatible.
+    pub fn peer_version(&self, peer_id: &PeerId) -> Option<ProtocolVersion> {
+        self.peers.get(peer_id)?.negotiated_version
+    }
+
+    /// Clones the peer info, filling in whether it is in the mesh of our topic.
+    #[inline]
+    fn with_mesh(&self, peer_id: &PeerId, info: &PeerInfo) -> PeerInfo {
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        let mut info = info.clone();
+        info.in_mesh = gossipsub
+            .mesh_peers(&self.topic.hash())
+            .any(|p| p == peer_id);
+        info
+    }
+
+    /// Returns the peers that have been disconnected due to their protocol version, with the reason.
+    pub fn incompatible_peers(&self) -> Vec<(PeerId, IncompatiblePeer)> {
+        self.incompatible_peers
+            .iter()
+            .map(|(peer_id, peer)| (*peer_id, peer.clone()))
+            .collect()
+    }
+
+    /// Returns the gossipsub scores of all known peers.
+    ///
+    /// Peers that misbehave, e.g. by sending messages that are rejected by the validator,
+    /// have negative scores; see [`GossipsubConfig`](crate::GossipsubConfig) for the thresholds.
+    pub fn peer_scores(&self) -> Vec<(PeerId, f64)> {
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        gossipsub
+            .all_peers()
+            .filter_map(|(peer_id, _)| Some((*peer_id, gossipsub.peer_score(peer_id)?)))
+            .collect()
+    }
+
+    /// Returns the latency histograms of the received messages, per topic and per author.
+    pub fn latencies(&self) -> LatencyStats {
+        self.latencies.clone()
+    }
+
+    /// Returns all metrics in the OpenMetrics text format, as served by the metrics endpoint.
+    pub fn metrics(&self) -> String {
+        self.metrics.encode()
+    }
+
+    /// Returns the traffic with each connected peer, per protocol, with the current rates.
+    pub fn bandwidth(&self) -> Vec<TrafficStats> {
+        self.bandwidth.stats()
+    }
+
+    /// Returns the depth of the worker queue, and how many messages it has dropped.
+    pub fn worker_stats(&self) -> WorkerStats {
+        self.workers.stats()
     }
 
-    pub async fn run(&mut self, port: u16) -> Result<(), ChatClientError> {
-        // start the client
-        self.start(port)?;
+    /// Whether the client has been run & stopped, which closes its channels for good.
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.command_channel.is_closed()
+    }
+
+    /// Pops the next file transfer event, such as progress or completion.
+    ///
+    /// Progress is coalesced per transfer, so only the latest one is kept until it is popped.
+    pub fn next_transfer_event(&mut self) -> Option<TransferEvent> {
+        self.transfers.next_event()
+    }
+
+    /// Runs the client until it is cancelled, then shuts it down gracefully.
+    ///
+    /// Returns what happened during the shutdown, see [`ChatConfig::shutdown`].
+    /// A client can only be run once: once stopped, this fails with [`ChatClientError::ClientStopped`].
+    pub async fn run(&mut self, port: u16) -> Result<ShutdownReport, ChatClientError> {
+        if self.is_stopped() {
+            return Err(ChatClientError::ClientStopped);
+        }
+
+        // start the client, or fail what the handles & senders are waiting for
+        if let Err(err) = self.start(port) {
+            self.stop().await;
+            return Err(err);
+        }
+
+        let mut bandwidth_interval = tokio::time::interval(self.config.bandwidth.rate_interval);
+        let mut outbox_interval = tokio::time::interval(self.config.outbox.retry_interval);
+        let mut prune_interval = tokio::time::interval(self.reassembler.prune_interval());
+        let mut rate_limit_interval = tokio::time::interval(self.rate_limiter.prune_interval());
+        let mut transfer_interval = tokio::time::interval(self.transfers.prune_interval());
+        let mut peer_interval = tokio::time::interval(peers::PRUNE_INTERVAL);
 
         loop {
             tokio::select! {
@@ -107,38 +591,111 @@ impl ChatClient {
                 _ = self.cancellation.cancelled() => break,
 
                 // check for messages to send
-                Some(message) = self.sender_channel.recv() => {
-                    if message.is_empty() {
-                        continue;
-                    }
+                Some(outgoing) = self.sender_channel.recv() => {
+                    self.handle_outgoing(outgoing);
+                }
 
-                    // publish the message
-                    if let Err(e) = self.publish(message) {
-                        log::error!("Error while publishing: {e}");
-                    }
+                // check for commands from handles
+                Some(command) = self.command_channel.recv() => self.handle_command(command),
+
+                // check for messages processed by the workers
+                Some(processed) = self.worker_results.recv() => self.handle_processed(processed),
+
+                // update the bandwidth rates
+                _ = bandwidth_interval.tick() => self.bandwidth.sample(),
+
+                // retry the messages waiting for peers
+                _ = outbox_interval.tick() => self.flush_outbox(),
+
+                // chunks of sets that have timed out will never be delivered
+                _ = prune_interval.tick() => {
+                    let expired = self.reassembler.prune();
+                    self.report_validation(expired, ValidationResult::Ignore);
                 }
 
+                // authors that have gone quiet would start over with a full bucket anyway
+                _ = rate_limit_interval.tick() => self.rate_limiter.prune(),
+
+                // close the files that peers have stopped pulling
+                _ = transfer_interval.tick() => self.transfers.prune(),
+
+                // forget the peers that left a while ago
+                _ = peer_interval.tick() => self.peers.prune(peers::DISCONNECTED_RETENTION),
+
                 // handle events
-                event = self.swarm.select_next_some() => match event {
-                    SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(event)) => self.handle_mdns(event),
-                    SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(event)) => self.handle_identify(event),
-                    SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(event)) => self.handle_gossipsub(event),
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        log::info!("Local node is listening on {address}");
-                    },
-                    SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                        log::info!("Connected closed with {peer_id}");
-                    },
-                    _ => {
-                        log::trace!("Unhandled event: {event:?}");
-                    }
-                }
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event).await,
             }
         }
 
-        self.stop();
+        Ok(self.stop().await)
+    }
 
-        Ok(())
+    /// Publishes a message from a [`ChatSender`], and lets it know how it went.
+    ///
+    /// Returns `false` if the message could not be published.
+    fn handle_outgoing(&mut self, outgoing: Outgoing) -> bool {
+        let Outgoing { data, ack } = outgoing;
+        match self.publish(data) {
+            Ok(Some(message_id)) => {
+                let _ = ack.send(Ok(message_id));
+                true
+            }
+            // acknowledged once published from the outbox
+            Ok(None) => {
+                self.outbox.set_last_ack(ack);
+                true
+            }
+            Err(e) => {
+                log::error!("Error while publishing: {e}");
+                let _ = ack.send(Err(e));
+                false
+            }
+        }
+    }
+
+    async fn handle_swarm_event(&mut self, event: SwarmEvent<ChatBehaviourEvent>) {
+        self.metrics.record(&event);
+        match event {
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(event)) => self.handle_mdns(event),
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(event)) => {
+                self.handle_identify(event)
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(event)) => {
+                self.handle_gossipsub(event).await
+            }
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Ping(event)) => self.handle_ping(event),
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Transfer(event)) => {
+                self.handle_transfer(event)
+            }
+            SwarmEvent::NewListenAddr { address, .. } => {
+                log::info!("Local node is listening on {address}");
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                num_established,
+                ..
+            } => {
+                self.peers.seen(peer_id).connections = num_established.get();
+                if self.rate_limiter.is_banned(&peer_id) {
+                    log::debug!("Refusing connection from banned peer {peer_id}");
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                ..
+            } => {
+                log::info!("Connected closed with {peer_id}");
+                self.peers.seen(peer_id).connections = num_established;
+                if num_established == 0 {
+                    self.transfers.on_disconnected(peer_id);
+                }
+            }
+            _ => {
+                log::trace!("Unhandled event: {event:?}");
+            }
+        }
     }
 
     #[inline]
@@ -148,6 +705,13 @@ impl ChatClient {
                 use libp2p::swarm::DialError;
 
                 for (peer_id, _multiaddr) in peers {
+                    // these would be denied at the connection level anyways
+                    if self.rate_limiter.is_banned(&peer_id)
+                        || !self.peer_lists.is_permitted(&peer_id)
+                    {
+                        continue;
+                    }
+
                     log::info!("mDNS discovered a new peer: {peer_id}");
                     // we dont add it yet, we instead wait for the identify event
                     match self.swarm.dial(peer_id) {
@@ -164,7 +728,7 @@ impl ChatClient {
             mdns::Event::Expired(peers) => {
                 for (peer_id, _multiaddr) in peers {
                     log::info!("mDNS discover peer has expired: {peer_id}");
-                    if let Err(_) = self.swarm.disconnect_peer_id(peer_id) {
+                    if self.swarm.disconnect_peer_id(peer_id).is_err() {
                         log::error!("Could not disconnect peer {peer_id}");
                     }
                 }
@@ -174,21 +738,32 @@ impl ChatClient {
 
     #[inline]
     fn handle_identify(&mut self, event: identify::Event) {
+        self.metrics.record(&event);
         match event {
             identify::Event::Received { peer_id, info, .. } => {
                 log::info!("Identified peer {peer_id}!");
-                if info.protocol_version != ChatBehaviour::PROTOCOL_VERSION {
-                    log::warn!(
-                        "Peer {peer_id} is using a different protocol version: {}, disconnecting.",
-                        info.protocol_version
-                    );
-                    let _ = self.swarm.disconnect_peer_id(peer_id);
-                } else {
-                    log::debug!("Adding peer {peer_id} to gossipsub explicit peers");
-                    self.swarm
-                        .behaviour_mut()
-                        .gossipsub
-                        .add_explicit_peer(&peer_id);
+                self.peers.identified(peer_id, &info);
+                let negotiated = ProtocolVersion::parse(&info.protocol_version)
+                    .and_then(|remote| ProtocolVersion::local().negotiate(&remote));
+                match negotiated {
+                    // compatible peers join the mesh on their own, subject to their score
+                    Ok(version) => {
+                        log::debug!("Peer {peer_id} is compatible ({version})");
+                        self.incompatible_peers.remove(&peer_id);
+                        self.peers.seen(peer_id).negotiated_version = Some(version);
+                    }
+                    Err(reason) => {
+                        log::warn!("Peer {peer_id} is incompatible: {reason}, disconnecting.");
+                        self.peers.seen(peer_id).negotiated_version = None;
+                        self.incompatible_peers.insert(
+                            peer_id,
+                            IncompatiblePeer {
+                                protocol_version: info.protocol_version,
+                                reason,
+                            },
+                        );
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                    }
                 }
             }
             _ => {
@@ -198,22 +773,113 @@ impl ChatClient {
     }
 
     #[inline]
-    fn handle_gossipsub(&mut self, event: gossipsub::Event) {
+    async fn handle_gossipsub(&mut self, event: gossipsub::Event) {
+        self.metrics.record(&event);
         match event {
             gossipsub::Event::Message {
                 message_id,
                 message,
-                propagation_source: peer_id,
+                propagation_source,
             } => {
-                // extract nanoseconds from the message
-                let _ =
-                    u64::from_ne_bytes(message.data[0..8].try_into().expect("should be 8 bytes"));
-                let message_str = String::from_utf8_lossy(&message.data[8..]);
+                // the message is signed, so the source is the original author
+                let peer_id = message.source.unwrap_or(propagation_source);
+                self.peers.seen(propagation_source);
                 log::debug!("Gossipsub message received: {message_id:?}");
-                log::info!("Message from {peer_id}:\n{message_str}");
 
-                // store the message in history
-                self.received.push_back((peer_id, message_str.to_string()));
+                let delivery = (message_id, propagation_source);
+
+                // reject authors that are flooding the topic, and disconnect repeat offenders;
+                // every chunk counts, so that incomplete sets can't bypass the limit. Relays of
+                // the flood are not penalized, they only forward what the author published.
+                let over_limit = if propagation_source == peer_id {
+                    ValidationResult::Reject
+                } else {
+                    ValidationResult::Ignore
+                };
+                match self.rate_limiter.check(peer_id) {
+                    RateLimit::Allowed => {}
+                    RateLimit::Limited => {
+                        log::debug!("Message from {peer_id} is over the rate limit");
+                        self.metrics.rejected(RejectReason::RateLimit);
+                        self.report_validation([delivery], over_limit);
+                        return;
+                    }
+                    RateLimit::Banned => {
+                        log::warn!("Peer {peer_id} keeps exceeding the rate limit, disconnecting.");
+                        self.metrics.rejected(RejectReason::RateLimit);
+                        self.report_validation([delivery], over_limit);
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                        return;
+                    }
+                }
+
+                let envelope = match Envelope::decode(&message.data) {
+                    Ok(envelope) => envelope,
+                    Err(err) => {
+                        log::warn!("Invalid message {} from {peer_id}: {err}", delivery.0);
+                        self.metrics.rejected(RejectReason::Invalid);
+                        self.report_validation([delivery], ValidationResult::Reject);
+                        return;
+                    }
+                };
+
+                // wait for the remaining chunks, if any
+                let (payload, deliveries) = match envelope.chunk {
+                    None => (envelope.payload, vec![delivery]),
+                    Some(chunk) => {
+                        let timestamp = envelope.timestamp;
+                        let payload = envelope.payload;
+                        match self.reassembler.insert(
+                            peer_id,
+                            timestamp,
+                            chunk,
+                            payload,
+                            delivery.clone(),
+                        ) {
+                            Reassembly::Pending => return,
+                            Reassembly::Complete {
+                                payload,
+                                deliveries,
+                            } => (payload, deliveries),
+                            Reassembly::Dropped => {
+                                self.report_validation([delivery], ValidationResult::Ignore);
+                                return;
+                            }
+                            Reassembly::Discarded { deliveries } => {
+                                self.metrics.rejected(RejectReason::TooLarge);
+                                self.report_validation(deliveries, ValidationResult::Reject);
+                                return;
+                            }
+                        }
+                    }
+                };
+
+                // decompression & validation are left to the workers, see `handle_processed`
+                let job = Job {
+                    author: peer_id,
+                    topic: message.topic,
+                    timestamp: envelope.timestamp,
+                    received_at: SystemTime::now(),
+                    compression: envelope.compression,
+                    payload,
+                    deliveries,
+                };
+                if let Some(dropped) = self.workers.submit(job).await {
+                    log::warn!(
+                        "Worker queue is full, dropping message from {}",
+                        dropped.author
+                    );
+                    self.report_validation(dropped.deliveries, ValidationResult::Ignore);
+                }
+            }
+            gossipsub::Event::Subscribed { peer_id, topic } => {
+                self.peers.seen(peer_id).topics.insert(topic);
+
+                // there may be someone to publish to now
+                self.flush_outbox();
+            }
+            gossipsub::Event::Unsubscribed { peer_id, topic } => {
+                self.peers.seen(peer_id).topics.remove(&topic);
             }
             _ => {
                 log::trace!("Unhandled gossipsub event: {event:?}");
@@ -221,6 +887,187 @@ impl ChatClient {
         }
     }
 
+    /// Reports the outcome of a message processed by the workers, and stores it if accepted.
+    #[inline]
+    fn handle_processed(&mut self, processed: Processed) {
+        let Processed {
+            author,
+            deliveries,
+            outcome,
+        } = processed;
+
+        let message = match outcome {
+            Outcome::Accepted(message) => message,
+            Outcome::Rejected(reason) => {
+                log::debug!("Message from {author} is rejected: {reason:?}");
+                self.metrics.rejected(reason);
+                self.report_validation(deliveries, ValidationResult::Reject);
+                return;
+            }
+            Outcome::Ignored => {
+                log::debug!("Message from {author} is ignored");
+                self.report_validation(deliveries, ValidationResult::Ignore);
+                return;
+            }
+        };
+
+        self.report_validation(deliveries, ValidationResult::Accept);
+        self.metrics.received();
+        log::info!("Message from {author}:\n{}", message.data);
+
+        match message.latency {
+            Some(latency) => {
+                log::debug!("Message from {author} took {latency:?}");
+                self.latencies.record(&message.topic, author, latency);
+            }
+            None => log::debug!("Message from {author} is from the future"),
+        }
+
+        // store the message in history
+        if let Some(dropped) = self.received.push(message) {
+            log::debug!(
+                "Received queue is full, dropped message from {}",
+                dropped.author
+            );
+        }
+    }
+
+    #[inline]
+    fn handle_ping(&mut self, event: ping::Event) {
+        self.metrics.record(&event);
+        match event.result {
+            Ok(rtt) => {
+                log::trace!("Ping to {} took {rtt:?}", event.peer);
+                self.peers
+                    .pinged(event.peer, rtt, self.config.ping.rtt_window);
+            }
+            Err(ping::Failure::Unsupported) => {
+                log::debug!("Peer {} does not support ping", event.peer);
+            }
+            Err(err) => {
+                // the connection is kept alive, so a dead one must be closed explicitly
+                log::warn!("Ping to {} failed: {err}, closing connection.", event.peer);
+                self.swarm.close_connection(event.connection);
+            }
+        }
+    }
+
+    /// Reports the validation result of the given messages to gossipsub.
+    #[inline]
+    fn report_validation(
+        &mut self,
+        deliveries: impl IntoIterator<Item = Delivery>,
+        result: ValidationResult,
+    ) {
+        let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+        for (message_id, propagation_source) in deliveries {
+            gossipsub.report_message_validation_result(
+                &message_id,
+                &propagation_source,
+                result.into(),
+            );
+        }
+    }
+
+    #[inline]
+    fn handle_transfer(&mut self, event: request_response::Event<FileRequest, FileResponse>) {
+        use request_response::{Event, Message};
+
+        let behaviour = &mut self.swarm.behaviour_mut().transfer;
+        match event {
+            Event::Message {
+                peer,
+                message:
+                    Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            } => {
+                let allowed = self.peer_lists.allowed.contains(&peer);
+                self.transfers
+                    .on_request(behaviour, peer, allowed, request, channel)
+            }
+            Event::Message {
+                message:
+                    Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => self.transfers.on_response(behaviour, request_id, response),
+            Event::OutboundFailure {
+                request_id, error, ..
+            } => self.transfers.on_failure(request_id, error.to_string()),
+            _ => {
+                log::trace!("Unhandled transfer event: {event:?}");
+            }
+        }
+    }
+
+    #[inline]
+    fn handle_command(&mut self, command: ChatCommand) {
+        match command {
+            ChatCommand::Publish { data, sender } => {
+                let _ = sender.send(self.publish(data));
+            }
+            ChatCommand::Receive { sender } => {
+                let _ = sender.send(self.received.pop_front());
+            }
+            ChatCommand::ReceiveWithin { max_len, sender } => {
+                let _ = sender.send(self.received.pop_front_within(max_len));
+            }
+            ChatCommand::PeekReceivedLen { sender } => {
+                let _ = sender.send(self.received.front().map(|message| message.data.len()));
+            }
+            ChatCommand::ReceivedDropped { sender } => {
+                let _ = sender.send(self.received.dropped());
+            }
+            ChatCommand::ForwardReceived { forward, sender } => {
+                self.forward_received(forward);
+                let _ = sender.send(());
+            }
+            #[cfg(unix)]
+            ChatCommand::ReceivedReadinessFd { sender } => {
+                let fd = self.received.readiness_fd();
+                let _ = sender.send(fd.map_err(ChatClientError::ReadinessError));
+            }
+            ChatCommand::SendFile {
+                peer_id,
+                path,
+                sender,
+            } => {
+                let _ = sender.send(self.send_file(peer_id, path));
+            }
+            ChatCommand::NextTransferEvent { sender } => {
+                let _ = sender.send(self.next_transfer_event());
+            }
+            ChatCommand::BlockPeer { peer_id, sender } => {
+                let _ = sender.send(self.block_peer(peer_id));
+            }
+            ChatCommand::AllowPeer { peer_id, sender } => {
+                let _ = sender.send(self.allow_peer(peer_id));
+            }
+            ChatCommand::Peers { sender } => {
+                let _ = sender.send(self.peers());
+            }
+            ChatCommand::IncompatiblePeers { sender } => {
+                let _ = sender.send(self.incompatible_peers());
+            }
+            ChatCommand::PeerScores { sender } => {
+                let _ = sender.send(self.peer_scores());
+            }
+            ChatCommand::Latencies { sender } => {
+                let _ = sender.send(self.latencies());
+            }
+            ChatCommand::Bandwidth { sender } => {
+                let _ = sender.send(self.bandwidth());
+            }
+            ChatCommand::WorkerStats { sender } => {
+                let _ = sender.send(self.worker_stats());
+            }
+        }
+    }
+
     /// Triggers the cancellation token, which will stop the client and all other tasks
     /// that may be waiting for this cancellation.
     #[inline]
@@ -228,44 +1075,147 @@ impl ChatClient {
         self.cancellation.cancel();
     }
 
-    /// Starts the client by subscribing to the chat topic and listening.
+    /// Starts the client by subscribing to the chat topic, listening, and dialing the bootstrap peers.
     ///
     /// Can be inlined as its only called once.
     #[inline]
     fn start(&mut self, port: u16) -> Result<(), ChatClientError> {
-        log::info!("Starting client on port {port}");
+        log::info!("Starting client {} on topic {}", self.peer_id(), self.topic);
         // subscribe
-        let topic = gossipsub::IdentTopic::new(Self::CHAT_TOPIC);
+        let topic = self.topic.clone();
         self.swarm
             .behaviour_mut()
             .gossipsub
             .subscribe(&topic)
             .map_err(ChatClientError::SubscribtionError)?;
 
-        // listen on all interfaces and whatever port the OS assigns
-        self.swarm
-            .listen_on(
+        // listen on all interfaces and the given port by default, `0` lets the OS assign one
+        let listen_addrs = match self.config.network.listen_addrs.as_slice() {
+            [] => vec![
                 format!("/ip4/0.0.0.0/tcp/{port}")
                     .parse()
                     .expect("should parse"),
-            )
-            .map_err(ChatClientError::ListenError)?;
+            ],
+            addrs => addrs.to_vec(),
+        };
+        for addr in listen_addrs {
+            self.swarm
+                .listen_on(addr)
+                .map_err(ChatClientError::ListenError)?;
+        }
+
+        // unreachable peers are not fatal, others may still be found
+        for addr in self.config.network.bootstrap.clone() {
+            log::info!("Dialing bootstrap peer {addr}");
+            if let Err(err) = self.swarm.dial(addr.clone()) {
+                log::error!("Could not dial bootstrap peer {addr}: {err}");
+            }
+        }
+
+        if let Some(listen_addr) = self.config.metrics.listen_addr {
+            self.metrics
+                .serve(listen_addr, self.cancellation.child_token())
+                .map_err(ChatClientError::MetricsError)?;
+        }
 
         Ok(())
     }
 
-    /// Starts the client by subscribing to the chat topic and listening.
-    ///
-    /// Can be inlined as its only called once.
     #[inline]
-    fn stop(&mut self) {
-        // unsubscribe
-        let topic = gossipsub::IdentTopic::new(Self::CHAT_TOPIC);
-        self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+    fn num_established(&self) -> usize {
+        self.swarm
+            .network_info()
+            .connection_counters()
+            .num_established() as usize
+    }
+
+    /// Stops the client gracefully, within the drain timeout.
+    ///
+    /// 1. Publishes the messages still queued by [`ChatSender`]s.
+    /// 2. Leaves the chat topic, and waits for the outbox to be published if there are peers.
+    /// 3. Closes all connections.
+    async fn stop(&mut self) -> ShutdownReport {
+        let started = Instant::now();
+        let deadline = started + self.config.shutdown.drain_timeout;
+        let mut report = ShutdownReport::default();
 
-        // close channel
+        // stop accepting messages, and publish those already queued
         self.sender_channel.close();
+        while let Ok(outgoing) = self.sender_channel.try_recv() {
+            report.drained += 1;
+            if !self.handle_outgoing(outgoing) {
+                report.failed += 1;
+            }
+        }
+
+        // let peers know we are leaving; we can still publish to them in the meantime
+        let topic = self.topic.clone();
+        self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+
+        // connection handlers are dropped as soon as their connection is closed, so give them
+        // some time to send what they have, and the outbox to be published
+        let flush = tokio::time::sleep(FLUSH_DELAY);
+        tokio::pin!(flush);
+        let mut flushed = false;
+        let mut outbox_interval = tokio::time::interval(self.config.outbox.retry_interval);
+        while !flushed || !self.outbox.is_empty() {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    report.timed_out = true;
+                    break;
+                }
+                _ = &mut flush, if !flushed => flushed = true,
+                _ = outbox_interval.tick() => self.flush_outbox(),
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event).await,
+            }
+        }
+
+        // close all connections, refusing new ones
+        let peers = self.swarm.connected_peers().copied().collect::<Vec<_>>();
+        for peer_id in peers {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+        while self.num_established() > 0 {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    report.timed_out = true;
+                    break;
+                }
+                event = self.swarm.select_next_some() => {
+                    self.metrics.record(&event);
+                    match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            let _ = self.swarm.disconnect_peer_id(peer_id);
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, num_established, .. } => {
+                            report.connections_closed += 1;
+                            self.peers.seen(peer_id).connections = num_established;
+                        }
+                        _ => log::trace!("Unhandled event while stopping: {event:?}"),
+                    }
+                }
+            }
+        }
+        report.connections_open = self.num_established();
+
+        // stop the workers, messages they won't get to are not penalized
+        for job in self.workers.close() {
+            self.report_validation(job.deliveries, ValidationResult::Ignore);
+        }
+
+        // commands & acknowledgements left unanswered fail with `ClientStopped`
+        self.command_channel.close();
+        while self.command_channel.try_recv().is_ok() {}
+        self.outbox.drop_acks();
+
+        // keep the unsent messages for the next run
+        report.unsent = self.outbox.len();
+        if let Err(err) = self.outbox.save() {
+            log::warn!("Could not save the outbox: {err}");
+        }
 
-        log::info!("Client stopped");
+        report.elapsed = started.elapsed();
+        log::info!("Client stopped: {report}");
+        report
     }
 }
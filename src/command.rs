@@ -0,0 +1,255 @@
+use crate::{ChatClientError, ChatMessage, IncompatiblePeer, LatencyStats, PeerInfo};
+use crate::{TrafficStats, TransferEvent, TransferId, WorkerStats};
+use libp2p::{PeerId, gossipsub::MessageId};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use tokio::sync::{mpsc, oneshot};
+
+/// Commands that can be sent to a running [`ChatClient`](crate::ChatClient).
+///
+/// Each command is paired with a `oneshot` sender, through which the client returns the result.
+#[derive(Debug)]
+pub enum ChatCommand {
+    /// Publish a message, see [`ChatClient::publish`](crate::ChatClient::publish).
+    Publish {
+        data: Vec<u8>,
+        sender: oneshot::Sender<Result<Option<MessageId>, ChatClientError>>,
+    },
+    /// Pop the oldest received message, see [`ChatClient::received`](crate::ChatClient::received).
+    Receive {
+        sender: oneshot::Sender<Option<ChatMessage>>,
+    },
+    /// Pop the oldest received message if it fits, see [`ReceivedQueue::pop_front_within`](crate::ReceivedQueue::pop_front_within).
+    ReceiveWithin {
+        max_len: usize,
+        sender: oneshot::Sender<Result<Option<ChatMessage>, usize>>,
+    },
+    /// Get the length of the oldest received message without removing it, see [`ReceivedQueue::front`](crate::ReceivedQueue::front).
+    PeekReceivedLen {
+        sender: oneshot::Sender<Option<usize>>,
+    },
+    /// Get the number of received messages dropped, see [`ReceivedQueue::dropped`](crate::ReceivedQueue::dropped).
+    ReceivedDropped { sender: oneshot::Sender<u64> },
+    /// Forward the received messages to a channel, see [`ChatClient::forward_received`](crate::ChatClient::forward_received).
+    ForwardReceived {
+        forward: Option<mpsc::Sender<ChatMessage>>,
+        sender: oneshot::Sender<()>,
+    },
+    /// Get the readiness descriptor of the received queue, see [`ReceivedQueue::readiness_fd`](crate::ReceivedQueue::readiness_fd).
+    #[cfg(unix)]
+    ReceivedReadinessFd {
+        sender: oneshot::Sender<Result<RawFd, ChatClientError>>,
+    },
+    /// Offer a file to a peer, see [`ChatClient::send_file`](crate::ChatClient::send_file).
+    SendFile {
+        peer_id: PeerId,
+        path: PathBuf,
+        sender: oneshot::Sender<Result<TransferId, ChatClientError>>,
+    },
+    /// Pop the next file transfer event, see [`ChatClient::next_transfer_event`](crate::ChatClient::next_transfer_event).
+    NextTransferEvent {
+        sender: oneshot::Sender<Option<TransferEvent>>,
+    },
+    /// Block a peer, see [`ChatClient::block_peer`](crate::ChatClient::block_peer).
+    BlockPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Result<(), ChatClientError>>,
+    },
+    /// Allow a peer, see [`ChatClient::allow_peer`](crate::ChatClient::allow_peer).
+    AllowPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Result<(), ChatClientError>>,
+    },
+    /// Get the peer table, see [`ChatClient::peers`](crate::ChatClient::peers).
+    Peers {
+        sender: oneshot::Sender<Vec<(PeerId, PeerInfo)>>,
+    },
+    /// Get the peers with incompatible versions, see [`ChatClient::incompatible_peers`](crate::ChatClient::incompatible_peers).
+    IncompatiblePeers {
+        sender: oneshot::Sender<Vec<(PeerId, IncompatiblePeer)>>,
+    },
+    /// Get the gossipsub scores of all known peers, see [`ChatClient::peer_scores`](crate::ChatClient::peer_scores).
+    PeerScores {
+        sender: oneshot::Sender<Vec<(PeerId, f64)>>,
+    },
+    /// Get the latency histograms of received messages, see [`ChatClient::latencies`](crate::ChatClient::latencies).
+    Latencies {
+        sender: oneshot::Sender<LatencyStats>,
+    },
+    /// Get the traffic per peer & protocol, see [`ChatClient::bandwidth`](crate::ChatClient::bandwidth).
+    Bandwidth {
+        sender: oneshot::Sender<Vec<TrafficStats>>,
+    },
+    /// Get the state of the worker pool, see [`ChatClient::worker_stats`](crate::ChatClient::worker_stats).
+    WorkerStats {
+        sender: oneshot::Sender<WorkerStats>,
+    },
+}
+
+/// A cloneable handle to talk with a running [`ChatClient`](crate::ChatClient) from other tasks or threads.
+///
+/// Obtained with [`ChatClient::handle`](crate::ChatClient::handle).
+#[derive(Debug, Clone)]
+pub struct ChatHandle {
+    pub(crate) commands: mpsc::UnboundedSender<ChatCommand>,
+}
+
+impl ChatHandle {
+    /// Publishes a message, and returns its id, or `None` if it is kept in the outbox.
+    ///
+    /// Unlike [`ChatSender::publish`](crate::ChatSender::publish), this does not wait for
+    /// messages in the outbox to be published.
+    pub async fn publish(
+        &self,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<Option<MessageId>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::Publish {
+            data: data.into(),
+            sender,
+        })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)?
+    }
+
+    /// Pops the oldest received message, if any.
+    pub async fn receive(&self) -> Result<Option<ChatMessage>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::Receive { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Pops the oldest received message if its data is at most `max_len` bytes, or returns its
+    /// length otherwise; see [`ReceivedQueue::pop_front_within`](crate::ReceivedQueue::pop_front_within).
+    pub async fn receive_within(
+        &self,
+        max_len: usize,
+    ) -> Result<Result<Option<ChatMessage>, usize>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::ReceiveWithin { max_len, sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns the length of the oldest received message, without removing it.
+    pub async fn peek_received_len(&self) -> Result<Option<usize>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::PeekReceivedLen { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns how many received messages have been dropped because the queue was full.
+    pub async fn received_dropped(&self) -> Result<u64, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::ReceivedDropped { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Forwards the received messages to the given channel instead of queueing them, see
+    /// [`ChatClient::forward_received`](crate::ChatClient::forward_received).
+    pub async fn forward_received(
+        &self,
+        forward: Option<mpsc::Sender<ChatMessage>>,
+    ) -> Result<(), ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::ForwardReceived { forward, sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns a file descriptor that is readable while the received queue is not empty, see
+    /// [`ReceivedQueue::readiness_fd`](crate::ReceivedQueue::readiness_fd).
+    #[cfg(unix)]
+    pub async fn received_readiness_fd(&self) -> Result<RawFd, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::ReceivedReadinessFd { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)?
+    }
+
+    /// Offers the file at `path` to the given peer, and returns the transfer id.
+    ///
+    /// Progress can be followed with [`ChatHandle::next_transfer_event`].
+    pub async fn send_file(
+        &self,
+        peer_id: PeerId,
+        path: impl Into<PathBuf>,
+    ) -> Result<TransferId, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::SendFile {
+            peer_id,
+            path: path.into(),
+            sender,
+        })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)?
+    }
+
+    /// Pops the next file transfer event, such as progress or completion.
+    pub async fn next_transfer_event(&self) -> Result<Option<TransferEvent>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::NextTransferEvent { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Blocks all connections with the given peer, and closes the existing ones.
+    pub async fn block_peer(&self, peer_id: PeerId) -> Result<(), ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::BlockPeer { peer_id, sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)?
+    }
+
+    /// Unblocks the given peer, and adds it to the allow list.
+    pub async fn allow_peer(&self, peer_id: PeerId) -> Result<(), ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::AllowPeer { peer_id, sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)?
+    }
+
+    /// Returns all peers we have come across, and what we know about them.
+    pub async fn peers(&self) -> Result<Vec<(PeerId, PeerInfo)>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::Peers { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns the peers that have been disconnected due to their protocol version.
+    pub async fn incompatible_peers(
+        &self,
+    ) -> Result<Vec<(PeerId, IncompatiblePeer)>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::IncompatiblePeers { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns the gossipsub scores of all known peers.
+    pub async fn peer_scores(&self) -> Result<Vec<(PeerId, f64)>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::PeerScores { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns the latency histograms of the received messages, per topic and per author.
+    pub async fn latencies(&self) -> Result<LatencyStats, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::Latencies { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns the traffic with each connected peer, per protocol, with the current rates.
+    pub async fn bandwidth(&self) -> Result<Vec<TrafficStats>, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::Bandwidth { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    /// Returns the depth of the worker queue, and how many messages it has dropped.
+    pub async fn worker_stats(&self) -> Result<WorkerStats, ChatClientError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ChatCommand::WorkerStats { sender })?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)
+    }
+
+    #[inline]
+    fn send(&self, command: ChatCommand) -> Result<(), ChatClientError> {
+        self.commands
+            .send(command)
+            .map_err(|_| ChatClientError::ClientStopped)
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{BandwidthConfig, Compression, ConnectionLimitsConfig, MetricsConfig};
+use crate::{OutboxConfig, OutgoingConfig, ShutdownConfig};
+use crate::{PeerListConfig, RateLimitConfig, ReceivedConfig, TransferConfig, WorkerConfig};
+use libp2p::{Multiaddr, identity::Keypair};
+use std::time::Duration;
+
+/// Configuration of the [`ChatClient`](crate::ChatClient) and its [`ChatBehaviour`](crate::ChatBehaviour).
+///
+/// Defaults are sensible for a local network, so one can start from [`Default::default`]
+/// and only change what is needed.
+#[derive(Debug, Clone, Default)]
+pub struct ChatConfig {
+    /// Identity, topic, addresses & peer discovery.
+    pub network: NetworkConfig,
+    /// Gossipsub configuration.
+    pub gossipsub: GossipsubConfig,
+    /// Rate limiting of received messages, per author.
+    pub rate_limit: RateLimitConfig,
+    /// Allow & block lists of peers.
+    pub peer_list: PeerListConfig,
+    /// File transfer configuration.
+    pub transfer: TransferConfig,
+    /// Ping & connection keep-alive configuration.
+    pub ping: PingConfig,
+    /// Prometheus metrics endpoint.
+    pub metrics: MetricsConfig,
+    /// Bandwidth accounting & per-peer caps.
+    pub bandwidth: BandwidthConfig,
+    /// Connection limits.
+    pub limits: ConnectionLimitsConfig,
+    /// Workers that process the received messages.
+    pub workers: WorkerConfig,
+    /// Queue of received messages, waiting to be read.
+    pub received: ReceivedConfig,
+    /// Channel of messages to be published.
+    pub outgoing: OutgoingConfig,
+    /// Messages kept until there are peers to publish them to.
+    pub outbox: OutboxConfig,
+    /// Graceful shutdown, once the client is cancelled.
+    pub shutdown: ShutdownConfig,
+}
+
+/// Configuration of the identity of the client, and how it finds its peers.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    /// Identity of the client, a new one is generated if `None`.
+    pub keypair: Option<Keypair>,
+    /// Gossipsub topic to chat on, see [`ChatClient::CHAT_TOPIC`](crate::ChatClient::CHAT_TOPIC).
+    ///
+    /// Only peers on the same topic receive each other's messages.
+    pub topic: String,
+    /// Addresses to listen on; if empty, the client listens on `0.0.0.0` and the port given to
+    /// [`ChatClient::run`](crate::ChatClient::run).
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Discover peers on the local network with mDNS.
+    pub mdns: bool,
+    /// Peers to dial once started, e.g. `/ip4/10.0.0.1/tcp/4001/p2p/12D3Koo...`.
+    ///
+    /// Needed to find peers beyond the local network, or when mDNS is disabled.
+    pub bootstrap: Vec<Multiaddr>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            keypair: None,
+            topic: crate::ChatClient::CHAT_TOPIC.into(),
+            listen_addrs: Vec::new(),
+            mdns: true,
+            bootstrap: Vec::new(),
+        }
+    }
+}
+
+/// Configuration of the gossipsub behaviour, and the messages published over it.
+#[derive(Debug, Clone)]
+pub struct GossipsubConfig {
+    /// Maximum size of a single gossipsub message; larger payloads are split into chunks.
+    ///
+    /// Must be the same across the network, as peers drop messages larger than their own limit,
+    /// and larger than the 525 bytes of headers that each message carries.
+    pub max_transmit_size: usize,
+    /// Maximum size of a payload, after its chunks are reassembled.
+    pub max_message_size: usize,
+    /// How long to wait for the missing chunks of a payload before discarding it.
+    pub chunk_timeout: Duration,
+    /// Maximum number of payloads waiting for their missing chunks; chunks of new payloads
+    /// are dropped beyond that.
+    pub max_partial_messages: usize,
+    /// Maximum number of payloads waiting for their missing chunks, per author.
+    pub max_partial_messages_per_author: usize,
+    /// Compression applied to published payloads, if any.
+    ///
+    /// Receivers decompress any supported algorithm regardless of this setting.
+    pub compression: Option<Compression>,
+    /// Payloads smaller than this many bytes are published uncompressed.
+    pub compression_threshold: usize,
+    /// Peers with a score below this won't receive gossip from us, and their gossip is ignored.
+    pub gossip_threshold: f64,
+    /// Peers with a score below this won't receive our own published messages.
+    pub publish_threshold: f64,
+    /// Peers with a score below this are ignored entirely.
+    pub graylist_threshold: f64,
+}
+
+impl Default for GossipsubConfig {
+    fn default() -> Self {
+        Self {
+            max_transmit_size: 64 * 1024,
+            max_message_size: 4 * 1024 * 1024,
+            chunk_timeout: Duration::from_secs(30),
+            max_partial_messages: 64,
+            max_partial_messages_per_author: 4,
+            compression: None,
+            compression_threshold: 1024,
+            gossip_threshold: -10.0,
+            publish_threshold: -50.0,
+            graylist_threshold: -80.0,
+        }
+    }
+}
+
+/// Configuration of the ping behaviour, which measures the round-trip time to each peer.
+#[derive(Debug, Clone)]
+pub struct PingConfig {
+    /// Time between two pings on a connection.
+    pub interval: Duration,
+    /// How long to wait for a pong before the ping is considered failed.
+    pub timeout: Duration,
+    /// Number of most recent round-trip times that are averaged per peer.
+    pub rtt_window: usize,
+    /// Keep connections open even when no protocol is using them.
+    ///
+    /// Failed pings close the connection instead, so that dead links don't go unnoticed.
+    pub keep_alive: bool,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(20),
+            rtt_window: 10,
+            keep_alive: true,
+        }
+    }
+}
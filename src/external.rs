@@ -6,14 +6,314 @@
 //! ```c
 //! typedef struct libp2p_chat_t;
 //! typedef struct libp2p_chat_handle libp2p_chat_handle_t;
+//! typedef struct libp2p_chat_config libp2p_chat_config_t;
 //! ```
 //!
 //! Each function in this module is prefixed with `libp2p_chat_` to avoid name clashes.
 //! They also have their declarations within their docstrings.
+//!
+//! These functions are called from C with raw pointers, so they are not marked `unsafe` on the Rust side.
+//!
+//! The pointer given to C is a [`FfiClient`], which can be used from any thread: once started,
+//! the client runs in its own thread and the other functions talk to it over its [`ChatHandle`].
+//!
+//! Received messages can either be polled with [`libp2p_chat_receive()`], or delivered to a C
+//! callback set with [`libp2p_chat_set_message_callback()`], which is called from a thread of its own.
+//!
+//! No function panics across the boundary: failures (and panics within) are turned into an
+//! [`FfiError`] code, and their message is kept for [`libp2p_chat_last_error()`].
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+use libp2p::{Multiaddr, identity::Keypair, multiaddr::Protocol};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread::JoinHandle;
+use std::time::UNIX_EPOCH;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
-use crate::ChatClient;
+use crate::{ChatClient, ChatClientError, ChatConfig, ChatHandle, ChatMessage, ShutdownReport};
+use crate::{TransferDirection, TransferEvent};
+
+/// Error codes returned by the FFI functions, declared as `libp2p_chat_error_t` in C.
+///
+/// The values are stable, new codes are only ever appended.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfiError {
+    /// A required pointer is `NULL`.
+    NullPointer = -1,
+    /// An argument is invalid, e.g. a peer id that can't be parsed.
+    InvalidArgument = -2,
+    /// The given buffer is too small for the message.
+    BufferTooSmall = -3,
+    /// The message exceeds the maximum message size.
+    MessageTooLarge = -4,
+    /// The message could not be published, e.g. the outbox is full or the message expired.
+    Publish = -5,
+    /// The file could not be sent.
+    Transfer = -6,
+    /// The client could not be created or run, e.g. it could not listen on the port.
+    Network = -7,
+    /// The client is already started.
+    AlreadyStarted = -8,
+    /// The client has been stopped.
+    Stopped = -9,
+    /// A panic was caught; the instance may be unusable afterwards.
+    Panic = -10,
+    /// An I/O error, e.g. the readiness descriptor could not be created.
+    Io = -11,
+}
+
+/// A failure within an FFI function, along with its message.
+struct Failure {
+    code: FfiError,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: FfiError, message: impl fmt::Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<ChatClientError> for Failure {
+    fn from(err: ChatClientError) -> Self {
+        let code = match &err {
+            ChatClientError::PublishError(_) | ChatClientError::OutboxExpired => FfiError::Publish,
+            ChatClientError::MessageTooLarge(_) => FfiError::MessageTooLarge,
+            ChatClientError::TransferError(_) => FfiError::Transfer,
+            ChatClientError::ClientStopped => FfiError::Stopped,
+            ChatClientError::ReadinessError(_) => FfiError::Io,
+            ChatClientError::SubscribtionError(_)
+            | ChatClientError::ListenError(_)
+            | ChatClientError::PeerListError(_)
+            | ChatClientError::MetricsError(_) => FfiError::Network,
+        };
+        Self::new(code, err)
+    }
+}
+
+thread_local! {
+    /// The last failure on this thread, see [`libp2p_chat_last_error()`].
+    static LAST_ERROR: RefCell<Option<(FfiError, CString)>> = const { RefCell::new(None) };
+}
+
+/// Runs the body of an FFI function, catching panics.
+///
+/// Failures are logged and kept as the last error of this thread.
+fn call<T>(f: impl FnOnce() -> Result<T, Failure>) -> Result<T, FfiError> {
+    let failure = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return Ok(value),
+        Ok(Err(failure)) => failure,
+        Err(payload) => Failure::new(FfiError::Panic, panic_message(payload.as_ref())),
+    };
+
+    log::error!("{}", failure.message);
+    // messages come from our own errors, which don't contain NUL bytes
+    let message = CString::new(failure.message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some((failure.code, message)));
+    Err(failure.code)
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("Panicked: {message}")
+}
+
+/// Dereferences a pointer given by C, failing if it is `NULL`.
+fn deref<'a, T>(ptr: *const T, name: &str) -> Result<&'a T, Failure> {
+    unsafe { ptr.as_ref() }
+        .ok_or_else(|| Failure::new(FfiError::NullPointer, format!("{name} is null")))
+}
+
+/// Same as [`deref`], for pointers that are modified.
+fn deref_mut<'a, T>(ptr: *mut T, name: &str) -> Result<&'a mut T, Failure> {
+    unsafe { ptr.as_mut() }
+        .ok_or_else(|| Failure::new(FfiError::NullPointer, format!("{name} is null")))
+}
+
+/// Reads a null-terminated string given by C, failing if it is `NULL` or not UTF-8.
+fn read_str<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    deref(ptr, name)?;
+    unsafe { CStr::from_ptr(ptr) }.to_str().map_err(|err| {
+        Failure::new(
+            FfiError::InvalidArgument,
+            format!("{name} is not UTF-8: {err}"),
+        )
+    })
+}
+
+/// Handle of the thread that runs the client, which gives the client back once stopped.
+type RunHandle = JoinHandle<(ChatClient, Result<ShutdownReport, ChatClientError>)>;
+
+/// A [`ChatClient`] shared with C/C++, which may call into it from any thread.
+pub struct FfiClient {
+    /// The client while it is not running; it is moved to its own thread in the meantime.
+    client: Mutex<Option<ChatClient>>,
+    /// Reaches the client while it is running.
+    handle: ChatHandle,
+    /// Set while the thread that runs the client is alive, see [`Running`].
+    running: Arc<AtomicBool>,
+    cancellation: CancellationToken,
+    /// Cancelled once freed, which stops the dispatcher even if the client is still running.
+    freed: CancellationToken,
+    /// Runtime that the client is created in, and run on.
+    runtime: Arc<Runtime>,
+    /// Thread that calls the message callback, if one is set.
+    dispatcher: Mutex<Option<JoinHandle<()>>>,
+    /// Capacity of the received queue, which bounds the messages waiting for the callback as well.
+    received_capacity: usize,
+}
+
+impl FfiClient {
+    /// Calls `idle` with the client if it is not running, otherwise blocks on `running` with its handle.
+    ///
+    /// Fails with [`ChatClientError::ClientStopped`] if the client has stopped on its own, e.g. it
+    /// could not listen, until it is given back by [`libp2p_chat_stop()`].
+    fn with_client<T, F>(
+        &self,
+        idle: impl FnOnce(&mut ChatClient) -> Result<T, ChatClientError>,
+        running: impl FnOnce(ChatHandle) -> F,
+    ) -> Result<T, ChatClientError>
+    where
+        F: Future<Output = Result<T, ChatClientError>>,
+    {
+        let mut client = self.client.lock().unwrap_or_else(PoisonError::into_inner);
+        match client.as_mut() {
+            Some(client) => idle(client),
+            None if !self.running.load(Ordering::Acquire) => Err(ChatClientError::ClientStopped),
+            None => {
+                // not to block other threads while waiting for the client; if it stops in the
+                // meantime, the command is dropped and this fails with `ClientStopped`
+                drop(client);
+                futures::executor::block_on(running(self.handle.clone()))
+            }
+        }
+    }
+}
+
+/// Clears [`FfiClient::running`] once the thread that runs the client ends, even by a panic.
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl Drop for FfiClient {
+    fn drop(&mut self) {
+        // a running client stops on its own, but may hold on to the dispatcher's channel for a while
+        self.cancellation.cancel();
+        self.freed.cancel();
+
+        // no callback is called once freed
+        let dispatcher = self
+            .dispatcher
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(dispatcher) = dispatcher {
+            join_dispatcher(dispatcher);
+        }
+    }
+}
+
+/// Called for each received message, see [`libp2p_chat_set_message_callback()`].
+pub type MessageCallback = extern "C" fn(
+    user_data: *mut c_void,
+    data: *const u8,
+    data_len: usize,
+    author: *const c_char,
+    timestamp: u64,
+);
+
+/// Pointer given by C along with the callback, which is passed back as is.
+struct UserData(*mut c_void);
+
+// SAFETY: C is responsible for the data being usable from the dispatcher thread, as documented.
+unsafe impl Send for UserData {}
+
+/// Calls the callback for each message forwarded by the client, until the client drops the channel
+/// or `freed` is cancelled.
+fn dispatch(
+    mut messages: mpsc::Receiver<ChatMessage>,
+    freed: CancellationToken,
+    callback: MessageCallback,
+    user_data: UserData,
+) {
+    let next = |messages: &mut mpsc::Receiver<ChatMessage>| {
+        futures::executor::block_on(async {
+            tokio::select! {
+                biased;
+                _ = freed.cancelled() => None,
+                message = messages.recv() => message,
+            }
+        })
+    };
+    while let Some(message) = next(&mut messages) {
+        // base58 has no NUL bytes
+        let author = CString::new(message.author.to_base58()).unwrap_or_default();
+        let timestamp = timestamp_millis(&message);
+        callback(
+            user_data.0,
+            message.data.as_ptr(),
+            message.data.len(),
+            author.as_ptr(),
+            timestamp,
+        );
+    }
+}
+
+/// Waits for the dispatcher to call the callback for the remaining messages, unless it is the caller.
+fn join_dispatcher(dispatcher: JoinHandle<()>) {
+    // e.g. the callback replaces itself
+    if dispatcher.thread().id() != std::thread::current().id() {
+        let _ = dispatcher.join();
+    }
+}
+
+/// Returns the message of the last error on the calling thread, or `NULL` if there is none.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern const char *libp2p_chat_last_error(void);
+/// ```
+///
+/// The string is owned by the library, and is valid until the next failing call on the same thread.
+/// Successful calls don't clear it, so it is only meaningful right after a call has failed.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some((_, message)) => message.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
+
+/// Returns the code of the last error on the calling thread, or `0` if there is none.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_last_error_code(void);
+/// ```
+///
+/// Useful for the functions that return a pointer, which is `NULL` on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_last_error_code() -> i32 {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(0, |(code, _)| *code as i32))
+}
 
 /// Enables logging for the library.
 ///
@@ -23,18 +323,21 @@ use crate::ChatClient;
 /// ```
 #[unsafe(no_mangle)]
 pub extern "C" fn libp2p_chat_enable_logs() {
-    if let Err(err) = env_logger::builder()
-        .filter(None, log::LevelFilter::Off)
-        .filter_module("libp2p_rustconnect", log::LevelFilter::Info)
-        .filter_module("libp2p", log::LevelFilter::Error)
-        .parse_default_env() // reads RUST_LOG variable
-        .try_init()
-    {
-        eprintln!("Could not enable logs: {err}");
-    }
+    let _ = call(|| {
+        if let Err(err) = env_logger::builder()
+            .filter(None, log::LevelFilter::Off)
+            .filter_module("libp2p_rustconnect", log::LevelFilter::Info)
+            .filter_module("libp2p", log::LevelFilter::Error)
+            .parse_default_env() // reads RUST_LOG variable
+            .try_init()
+        {
+            eprintln!("Could not enable logs: {err}");
+        }
+        Ok(())
+    });
 }
 
-/// Creates a new chat client.
+/// Creates a new chat client, with the default configuration.
 ///
 /// To be declared in C/C++ as:
 /// ```c
@@ -42,45 +345,269 @@ pub extern "C" fn libp2p_chat_enable_logs() {
 /// ```
 ///
 /// Must be freed with [`libp2p_chat_free()`], otherwise will cause a **memory leak**.
+/// Returns `NULL` on error, see [`libp2p_chat_last_error()`].
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_new() -> *mut FfiClient {
+    call(|| new_client(ChatConfig::default())).unwrap_or(std::ptr::null_mut())
+}
+
+/// Creates a new chat client with the given configuration, see [`libp2p_chat_config_new()`].
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern libp2p_chat_t* libp2p_chat_new_with_config(const libp2p_chat_config_t* config);
+/// ```
+///
+/// The configuration is copied, so it can be freed (or reused) right after.
+/// Must be freed with [`libp2p_chat_free()`], otherwise will cause a **memory leak**.
+/// Returns `NULL` on error, see [`libp2p_chat_last_error()`].
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_new_with_config(config_ptr: *const ChatConfig) -> *mut FfiClient {
+    call(|| new_client(deref(config_ptr, "config")?.clone())).unwrap_or(std::ptr::null_mut())
+}
+
+fn new_client(config: ChatConfig) -> Result<*mut FfiClient, Failure> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| {
+            Failure::new(
+                FfiError::Network,
+                format!("Could not create runtime: {err}"),
+            )
+        })?;
+
+    // some behaviours (e.g. mDNS) must be created within the runtime
+    let cancellation = CancellationToken::new();
+    let (client, _ /* messages are published through the handle instead */) = {
+        let _guard = runtime.enter();
+        ChatClient::with_config(config, cancellation.clone()).map_err(|err| {
+            Failure::new(FfiError::Network, format!("Could not create client: {err}"))
+        })?
+    };
+    Ok(Box::into_raw(Box::new(FfiClient {
+        handle: client.handle(),
+        running: Default::default(),
+        received_capacity: client.received.capacity(),
+        client: Mutex::new(Some(client)),
+        cancellation,
+        freed: CancellationToken::new(),
+        runtime: Arc::new(runtime),
+        dispatcher: Mutex::new(None),
+    })))
+}
+
+/// Creates a configuration for [`libp2p_chat_new_with_config()`], with the defaults.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern libp2p_chat_config_t* libp2p_chat_config_new(void);
+/// ```
+///
+/// Must be freed with [`libp2p_chat_config_free()`], otherwise will cause a **memory leak**.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_config_new() -> *mut ChatConfig {
+    call(|| Ok(Box::into_raw(Box::default()))).unwrap_or(std::ptr::null_mut())
+}
+
+/// Frees the memory allocated for the configuration.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern void libp2p_chat_config_free(libp2p_chat_config_t* config);
+/// ```
+///
+/// Does no action if the pointer is `NULL`.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_config_free(config_ptr: *mut ChatConfig) {
+    if config_ptr.is_null() {
+        return;
+    }
+
+    let _ = call(|| {
+        unsafe {
+            drop(Box::from_raw(config_ptr));
+        }
+        Ok(())
+    });
+}
+
+/// Sets the identity of the client, from the 32 bytes of an ed25519 secret key.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_config_set_identity_key(libp2p_chat_config_t* config, const uint8_t* key, size_t key_len);
+/// ```
+///
+/// A new identity is generated for each client otherwise, so this keeps the peer id across restarts.
+/// Returns `0` on success, or an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_config_set_identity_key(
+    config_ptr: *mut ChatConfig,
+    key_ptr: *const u8,
+    key_len: usize,
+) -> i32 {
+    call(|| {
+        let config = deref_mut(config_ptr, "config")?;
+        deref(key_ptr, "key")?;
+        let key = unsafe { std::slice::from_raw_parts(key_ptr, key_len) };
+
+        let keypair = Keypair::ed25519_from_bytes(key.to_vec()).map_err(|err| {
+            Failure::new(
+                FfiError::InvalidArgument,
+                format!("Invalid identity key: {err}"),
+            )
+        })?;
+        config.network.keypair = Some(keypair);
+        Ok(0)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Sets the gossipsub topic to chat on; only clients on the same topic see each other's messages.
+///
+/// The topic must fit in [`FfiMetadata::topic`], along with its null-terminator.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_config_set_topic(libp2p_chat_config_t* config, const char* topic);
+/// ```
+///
+/// Returns `0` on success, or an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_config_set_topic(
+    config_ptr: *mut ChatConfig,
+    topic_ptr: *const c_char,
+) -> i32 {
+    call(|| {
+        let config = deref_mut(config_ptr, "config")?;
+        let topic = read_str(topic_ptr, "topic")?;
+        if topic.is_empty() || topic.len() >= TOPIC_SIZE {
+            return Err(Failure::new(
+                FfiError::InvalidArgument,
+                format!("Topic must be 1 to {} bytes long", TOPIC_SIZE - 1),
+            ));
+        }
+
+        config.network.topic = topic.to_string();
+        Ok(0)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Adds a multiaddress to listen on, e.g. `/ip4/127.0.0.1/tcp/4001`.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_config_add_listen_addr(libp2p_chat_config_t* config, const char* addr);
+/// ```
+///
+/// Once any is added, the port given to [`libp2p_chat_start()`] is ignored.
+/// Returns `0` on success, or an error code.
 #[unsafe(no_mangle)]
-pub extern "C" fn libp2p_chat_new() -> *mut ChatClient {
-    let (client, _ /* we could return a pointer to channel too! */) =
-        ChatClient::new(CancellationToken::new()).expect("could not create LibP2P");
-    Box::into_raw(Box::new(client))
+pub extern "C" fn libp2p_chat_config_add_listen_addr(
+    config_ptr: *mut ChatConfig,
+    addr_ptr: *const c_char,
+) -> i32 {
+    call(|| {
+        let config = deref_mut(config_ptr, "config")?;
+        let addr = read_multiaddr(addr_ptr, "addr")?;
+        config.network.listen_addrs.push(addr);
+        Ok(0)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Enables or disables the discovery of peers on the local network with mDNS, enabled by default.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_config_set_mdns(libp2p_chat_config_t* config, bool enabled);
+/// ```
+///
+/// Returns `0` on success, or an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_config_set_mdns(config_ptr: *mut ChatConfig, enabled: bool) -> i32 {
+    call(|| {
+        deref_mut(config_ptr, "config")?.network.mdns = enabled;
+        Ok(0)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Adds a peer to dial once started, as a multiaddress ending with its peer id,
+/// e.g. `/ip4/10.0.0.1/tcp/4001/p2p/12D3Koo...`.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_config_add_bootstrap_peer(libp2p_chat_config_t* config, const char* addr);
+/// ```
+///
+/// Returns `0` on success, or an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_config_add_bootstrap_peer(
+    config_ptr: *mut ChatConfig,
+    addr_ptr: *const c_char,
+) -> i32 {
+    call(|| {
+        let config = deref_mut(config_ptr, "config")?;
+        let addr = read_multiaddr(addr_ptr, "addr")?;
+        if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+            return Err(Failure::new(
+                FfiError::InvalidArgument,
+                format!("Bootstrap peer {addr} does not end with /p2p/<peer id>"),
+            ));
+        }
+
+        config.network.bootstrap.push(addr);
+        Ok(0)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+fn read_multiaddr(ptr: *const c_char, name: &str) -> Result<Multiaddr, Failure> {
+    let addr = read_str(ptr, name)?;
+    addr.parse().map_err(|err| {
+        Failure::new(
+            FfiError::InvalidArgument,
+            format!("Invalid multiaddress {addr:?}: {err}"),
+        )
+    })
 }
 
 /// Gracefully shutdown the chat client.
 ///
-/// This first calls `cancel()` on the client, and then waits for the handle to finish.
-/// It is expected to finish due to the internal cancellation token.
+/// This first cancels the client, and then waits for the handle to finish.
+/// The stopped client is kept, so the messages it has received can still be read,
+/// but it cannot be started again.
 ///
 /// To be declared in C/C++ as:
 /// ```c
 /// extern int libp2p_chat_stop(libp2p_chat_t* ptr, libp2p_chat_handle_t* handle_ptr);
 /// ```
+///
+/// Returns `0` on success, or an error code if the client failed while running; the handle is
+/// consumed either way.
 #[unsafe(no_mangle)]
 pub extern "C" fn libp2p_chat_stop(
-    client_ptr: *mut ChatClient,
-    handle_ptr: *mut JoinHandle<()>,
+    client_ptr: *const FfiClient,
+    handle_ptr: *mut RunHandle,
 ) -> i32 {
-    let client = unsafe {
-        assert!(!client_ptr.is_null(), "libp2p_ptr is null");
-        &mut *client_ptr
-    };
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        deref(handle_ptr, "handle_ptr")?;
+        let handle = unsafe { Box::from_raw(handle_ptr) };
 
-    let handle = unsafe {
-        assert!(!handle_ptr.is_null(), "handle_ptr is null");
-        Box::from_raw(handle_ptr)
-    };
+        client.cancellation.cancel();
+        let (stopped, result) = handle
+            .join()
+            .map_err(|payload| Failure::new(FfiError::Panic, panic_message(payload.as_ref())))?;
 
-    client.cancel();
-    match handle.join() {
-        Ok(_) => 0,
-        Err(err) => {
-            log::error!("Could not stopp the client: {err:?}");
-            -1
-        }
-    }
+        // keep it around, so that the remaining messages can still be received
+        *client.client.lock().unwrap_or_else(PoisonError::into_inner) = Some(stopped);
+        result.map(|_| 0).map_err(Failure::from)
+    })
+    .unwrap_or_else(|code| code as i32)
 }
 
 /// Frees the memory allocated for the `LibP2P` instance.
@@ -90,18 +617,22 @@ pub extern "C" fn libp2p_chat_stop(
 /// extern void libp2p_chat_free(libp2p_chat_t* ptr);
 /// ```
 ///
-/// Does no action if the pointer is `NULL`.
+/// Once this returns, the message callback is no longer called, even if the client was still
+/// running, so its `user_data` can be freed. Does no action if the pointer is `NULL`.
 #[unsafe(no_mangle)]
-pub extern "C" fn libp2p_chat_free(chat_ptr: *mut ChatClient) {
+pub extern "C" fn libp2p_chat_free(chat_ptr: *mut FfiClient) {
     if chat_ptr.is_null() {
         return;
     }
 
     // since the object was allocated by Rust, it must be freed by Rust as well;
     // so we use `Box::from_raw` to convert the raw pointer back into a `Box` and then drop it (explicitly).
-    unsafe {
-        drop(Box::from_raw(chat_ptr));
-    }
+    let _ = call(|| {
+        unsafe {
+            drop(Box::from_raw(chat_ptr));
+        }
+        Ok(())
+    });
 }
 
 /// Starts the chat client in a new thread, and returns a join handle.
@@ -112,23 +643,51 @@ pub extern "C" fn libp2p_chat_free(chat_ptr: *mut ChatClient) {
 /// ```
 ///
 /// The returned handle should be passed to [`libp2p_chat_stop()`] to stop the daemon gracefully.
+/// Returns `NULL` on error, such as when the client is already started, or has been stopped.
+///
+/// Other functions can be called from any thread in the meantime. If the client stops on its
+/// own, e.g. it can't listen on the port, they fail with [`FfiError::Stopped`] until
+/// [`libp2p_chat_stop()`] returns why.
 #[unsafe(no_mangle)]
-pub extern "C" fn libp2p_chat_start(client_ptr: *mut ChatClient, port: u16) -> *mut JoinHandle<()> {
-    let client = unsafe {
-        assert!(!client_ptr.is_null());
-        &mut *client_ptr
-    };
-
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .expect("could not create runtime");
+pub extern "C" fn libp2p_chat_start(client_ptr: *const FfiClient, port: u16) -> *mut RunHandle {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        let mut idle = client.client.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut chat = match idle.take() {
+            Some(chat) if chat.is_stopped() => {
+                *idle = Some(chat);
+                return Err(Failure::new(
+                    FfiError::Stopped,
+                    "Client has been stopped, and cannot be started again",
+                ));
+            }
+            Some(chat) => chat,
+            None => {
+                return Err(Failure::new(
+                    FfiError::AlreadyStarted,
+                    "Client is already started",
+                ));
+            }
+        };
+        // before other threads can see that the client is gone
+        client.running.store(true, Ordering::Release);
+        drop(idle);
 
-    let handle = std::thread::spawn(move || {
-        rt.block_on(async { client.run(port).await.expect("could not run the client") });
-    });
+        let rt = client.runtime.clone();
+        let running = Running(client.running.clone());
+        let handle = std::thread::spawn(move || {
+            let _running = running;
+            // the shutdown report is logged by the client
+            let result = rt.block_on(chat.run(port));
+            if let Err(err) = &result {
+                log::error!("Could not run the client: {err}");
+            }
+            (chat, result)
+        });
 
-    Box::into_raw(Box::new(handle))
+        Ok(Box::into_raw(Box::new(handle)))
+    })
+    .unwrap_or(std::ptr::null_mut())
 }
 
 /// Sends raw bytes to all connected peers in the network.
@@ -138,27 +697,31 @@ pub extern "C" fn libp2p_chat_start(client_ptr: *mut ChatClient, port: u16) -> *
 /// extern int libp2p_chat_publish(libp2p_chat_t* ptr, const char* data, size_t data_len);
 /// ```
 ///
-/// Returns non-zero on error, such as when there are no peers to send a message to.
+/// Messages published before any peers are found are kept in the outbox, and published
+/// once a peer subscribes to the topic; see `ChatConfig::outbox`.
+///
+/// Returns `0` on success; otherwise, an error code such as when the message is too large,
+/// or the outbox is full.
 #[unsafe(no_mangle)]
-pub fn libp2p_chat_publish(
-    client_ptr: *mut ChatClient,
+pub extern "C" fn libp2p_chat_publish(
+    client_ptr: *const FfiClient,
     data_ptr: *const u8,
     data_len: usize,
 ) -> i32 {
-    let client = unsafe {
-        assert!(!client_ptr.is_null());
-        &mut *client_ptr
-    };
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        deref(data_ptr, "data")?;
+        let data = unsafe { std::slice::from_raw_parts(data_ptr, data_len) };
 
-    let data = unsafe { std::slice::from_raw_parts(data_ptr, data_len) };
-
-    match client.publish(data) {
-        Ok(_) => 0,
-        Err(err) => {
-            log::error!("Could not publish message: {err:?}");
-            -1
-        }
-    }
+        client
+            .with_client(
+                |client| client.publish(data),
+                |handle| async move { handle.publish(data).await },
+            )
+            .map(|_| 0)
+            .map_err(Failure::from)
+    })
+    .unwrap_or_else(|code| code as i32)
 }
 
 /// Pops a message from the chat client.
@@ -168,32 +731,483 @@ pub fn libp2p_chat_publish(
 /// extern int libp2p_chat_receive(libp2p_chat_t *ptr, void *buf, size_t buf_size);
 /// ```
 ///
-/// Returns the number of bytes received on success; othewrwise, returns -1.
+/// Returns the number of bytes received on success; otherwise, returns an error code.
+/// A message that does not fit in the buffer is consumed nevertheless, and `0` is returned for both
+/// an empty queue and an empty message; see [`libp2p_chat_receive_with_metadata()`] to avoid both.
 #[unsafe(no_mangle)]
-pub fn libp2p_chat_receive(client_ptr: *mut ChatClient, buf: *const u8, buf_size: usize) -> i32 {
-    let client = unsafe {
-        assert!(!client_ptr.is_null());
-        &mut *client_ptr
-    };
+pub extern "C" fn libp2p_chat_receive(
+    client_ptr: *const FfiClient,
+    buf: *const u8,
+    buf_size: usize,
+) -> i32 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        deref(buf, "buf")?;
 
-    if let Some((_, msg)) = client.received.pop_front() {
+        let received: Result<Option<ChatMessage>, ChatClientError> = client.with_client(
+            |client| Ok(client.received.pop_front()),
+            |handle| async move { handle.receive().await },
+        );
+        let Some(message) = received? else {
+            return Ok(0);
+        };
+
+        let msg = message.data;
         let msg_len: usize = msg.len();
 
-        if msg_len == 0 {
-            // if the message is empty, we cannot copy the data
-            // but the message is consumed nevertheless
-            0
-        } else if buf_size < msg_len {
+        if buf_size < msg_len {
             // if the buffer is too small, we cannot copy the data
             // but the message is consumed nevertheless
-            -1
-        } else {
+            return Err(Failure::new(
+                FfiError::BufferTooSmall,
+                format!("Message of {msg_len} bytes does not fit in {buf_size} bytes"),
+            ));
+        }
+
+        // an empty message copies nothing
+        unsafe {
+            std::ptr::copy_nonoverlapping(msg.as_ptr(), buf as *mut u8, msg_len);
+        }
+        Ok(msg_len as i32)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Sets the function to call for each received message, instead of storing them for [`libp2p_chat_receive()`].
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// typedef void (*libp2p_chat_message_cb)(void *user_data, const uint8_t *data, size_t data_len,
+///                                        const char *author, uint64_t timestamp);
+/// extern int libp2p_chat_set_message_callback(libp2p_chat_t *ptr, libp2p_chat_message_cb callback, void *user_data);
+/// ```
+///
+/// The callback is called from a thread of the library, one message at a time as they
+/// are received, with `user_data` as given. `data` and `author` (a null-terminated peer id) are only
+/// valid during the call, and `timestamp` is when the author published the message, in milliseconds
+/// since the Unix epoch. Other functions may be called from the callback, except [`libp2p_chat_free()`].
+///
+/// Messages wait for the callback in a queue as large as the received queue; those that arrive
+/// while it is full are dropped, and counted by [`libp2p_chat_received_dropped()`]. Messages that
+/// were already stored can still be received with [`libp2p_chat_receive()`].
+///
+/// A `NULL` callback stores the messages again. Once this returns, the previous callback has been
+/// called for its remaining messages and is no longer called, so its `user_data` can be freed.
+/// The same holds for the current callback once [`libp2p_chat_free()`] returns.
+///
+/// Returns `0` on success, or an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_set_message_callback(
+    client_ptr: *const FfiClient,
+    callback: Option<MessageCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+
+        let mut dispatcher = client
+            .dispatcher
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (forward, thread) = match callback {
+            Some(callback) => {
+                let (forward, messages) = mpsc::channel(client.received_capacity);
+                let user_data = UserData(user_data);
+                let freed = client.freed.clone();
+                let thread =
+                    std::thread::spawn(move || dispatch(messages, freed, callback, user_data));
+                (Some(forward), Some(thread))
+            }
+            None => (None, None),
+        };
+
+        // the previous dispatcher stops once its channel is replaced, and so does the new one on error
+        let running_forward = forward.clone();
+        client
+            .with_client(
+                |client| {
+                    client.forward_received(forward);
+                    Ok(())
+                },
+                |handle| async move { handle.forward_received(running_forward).await },
+            )
+            .map_err(Failure::from)?;
+
+        let previous = std::mem::replace(&mut *dispatcher, thread);
+        // not to block concurrent calls, e.g. from within the previous callback
+        drop(dispatcher);
+        if let Some(previous) = previous {
+            join_dispatcher(previous);
+        }
+        Ok(0)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Size of [`FfiMetadata::author`], which fits any peer id.
+const PEER_ID_SIZE: usize = 128;
+/// Size of [`FfiMetadata::topic`], which bounds the topic set with [`libp2p_chat_config_set_topic()`].
+const TOPIC_SIZE: usize = 256;
+
+/// Metadata of a received message, declared as `libp2p_chat_metadata_t` in C.
+#[repr(C)]
+pub struct FfiMetadata {
+    /// Peer id of the author, null-terminated.
+    pub author: [c_char; PEER_ID_SIZE],
+    /// Topic the message was published to, null-terminated.
+    pub topic: [c_char; TOPIC_SIZE],
+    /// When the author published the message, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Length of the message, in bytes.
+    pub data_len: usize,
+}
+
+/// Copies `src` into `dst` as a null-terminated string, truncated if needed.
+fn copy_str(dst: &mut [c_char], src: &str) {
+    let len = src.len().min(dst.len() - 1);
+    for (dst, src) in dst.iter_mut().zip(&src.as_bytes()[..len]) {
+        *dst = *src as c_char;
+    }
+    dst[len] = 0;
+}
+
+/// Returns when the message was published, in milliseconds since the Unix epoch.
+fn timestamp_millis(message: &ChatMessage) -> u64 {
+    message
+        .sent_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Reports the length of the next message, without removing it from the queue.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_peek(const libp2p_chat_t *ptr, size_t *data_len);
+/// ```
+///
+/// `data_len` may be `NULL`, to only check whether a message is waiting.
+/// Returns `1` if a message is waiting, `0` if the queue is empty; otherwise, returns an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_peek(client_ptr: *const FfiClient, data_len: *mut usize) -> i32 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        let len = client.with_client(
+            |client| Ok(client.received.front().map(|message| message.data.len())),
+            |handle| async move { handle.peek_received_len().await },
+        );
+        let Some(len) = len? else {
+            return Ok(0);
+        };
+        if let Some(data_len) = unsafe { data_len.as_mut() } {
+            *data_len = len;
+        }
+        Ok(1)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Pops a message from the chat client, along with its author, topic and timestamp.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_receive_with_metadata(libp2p_chat_t *ptr, void *buf, size_t buf_size, libp2p_chat_metadata_t *metadata);
+/// ```
+///
+/// A message that does not fit in the buffer is left in the queue, and fails with
+/// [`FfiError::BufferTooSmall`]; its length can be read with [`libp2p_chat_peek()`].
+/// `buf` may be `NULL` if `buf_size` is `0`, to receive empty messages only.
+///
+/// Returns `1` if a message is received (which may be empty, see `data_len`), `0` if the queue is
+/// empty; otherwise, returns an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_receive_with_metadata(
+    client_ptr: *const FfiClient,
+    buf: *mut u8,
+    buf_size: usize,
+    metadata_ptr: *mut FfiMetadata,
+) -> i32 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        let metadata = deref_mut(metadata_ptr, "metadata")?;
+        if buf_size > 0 {
+            deref(buf, "buf")?;
+        }
+
+        let received = client
+            .with_client(
+                |client| Ok(client.received.pop_front_within(buf_size)),
+                |handle| async move { handle.receive_within(buf_size).await },
+            )
+            .map_err(Failure::from)?;
+        let message = match received {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(0),
+            Err(len) => {
+                return Err(Failure::new(
+                    FfiError::BufferTooSmall,
+                    format!("Message of {len} bytes does not fit in {buf_size} bytes"),
+                ));
+            }
+        };
+
+        let data = message.data.as_bytes();
+        if !data.is_empty() {
             unsafe {
-                std::ptr::copy_nonoverlapping(msg.as_ptr(), buf as *mut u8, msg_len);
+                std::ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
             }
-            msg_len as i32
         }
-    } else {
-        0
+        copy_str(&mut metadata.author, &message.author.to_base58());
+        copy_str(&mut metadata.topic, message.topic.as_str());
+        metadata.timestamp = timestamp_millis(&message);
+        metadata.data_len = data.len();
+        Ok(1)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Offers a file to the given peer, which downloads it in chunks and verifies its hash.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int64_t libp2p_chat_send_file(libp2p_chat_t *ptr, const char *peer_id, const char *path);
+/// ```
+///
+/// The transfer begins once the client is started with [`libp2p_chat_start()`].
+/// Interrupted transfers are resumed by sending the same file again.
+///
+/// Returns the transfer id (non-negative) on success; otherwise, returns an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_send_file(
+    client_ptr: *const FfiClient,
+    peer_id_ptr: *const c_char,
+    path_ptr: *const c_char,
+) -> i64 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        deref(peer_id_ptr, "peer_id")?;
+        deref(path_ptr, "path")?;
+
+        let (peer_id, path) = unsafe { (CStr::from_ptr(peer_id_ptr), CStr::from_ptr(path_ptr)) };
+        let Some(peer_id) = peer_id.to_str().ok().and_then(|p| p.parse().ok()) else {
+            return Err(Failure::new(
+                FfiError::InvalidArgument,
+                format!("Invalid peer id: {peer_id:?}"),
+            ));
+        };
+        let path = path.to_string_lossy();
+        let path = path.as_ref();
+
+        client
+            .with_client(
+                |client| client.send_file(peer_id, path),
+                |handle| async move { handle.send_file(peer_id, path).await },
+            )
+            .map(|id| id as i64)
+            .map_err(Failure::from)
+    })
+    .unwrap_or_else(|code| code as i64)
+}
+
+/// Size of [`FfiTransferEvent::detail`], longer names, paths & reasons are truncated.
+const TRANSFER_DETAIL_SIZE: usize = 1024;
+
+/// Kind of a [`FfiTransferEvent`], declared as `libp2p_chat_transfer_kind_t` in C.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfiTransferKind {
+    /// Transfer has started, resuming from `bytes` if non-zero; `detail` is the file name.
+    Started = 0,
+    /// Transfer has progressed up to `bytes` out of `size`.
+    Progress = 1,
+    /// Transfer has finished and the hash is verified; `detail` is the path of the file.
+    Completed = 2,
+    /// Transfer has failed; `detail` is the reason.
+    Failed = 3,
+}
+
+/// A file transfer event, declared as `libp2p_chat_transfer_event_t` in C.
+#[repr(C)]
+pub struct FfiTransferEvent {
+    /// What happened to the transfer.
+    pub kind: FfiTransferKind,
+    /// Whether the file is received from the peer, rather than sent to it.
+    pub incoming: bool,
+    /// Transfer id, unique per peer & direction.
+    pub id: u64,
+    /// Peer id of the other side, null-terminated.
+    pub peer_id: [c_char; PEER_ID_SIZE],
+    /// Number of bytes transferred so far, `0` if unknown.
+    pub bytes: u64,
+    /// Size of the file, `0` if unknown.
+    pub size: u64,
+    /// File name, path or reason depending on `kind`, null-terminated.
+    pub detail: [c_char; TRANSFER_DETAIL_SIZE],
+}
+
+impl FfiTransferEvent {
+    /// Fills in the fields from `event`.
+    fn fill(&mut self, event: TransferEvent) {
+        let (kind, peer_id, id, direction, bytes, size, detail) = match event {
+            TransferEvent::Started {
+                peer_id,
+                id,
+                direction,
+                name,
+                size,
+                offset,
+            } => (
+                FfiTransferKind::Started,
+                peer_id,
+                id,
+                direction,
+                offset,
+                size,
+                name,
+            ),
+            TransferEvent::Progress {
+                peer_id,
+                id,
+                direction,
+                bytes,
+                size,
+            } => (
+                FfiTransferKind::Progress,
+                peer_id,
+                id,
+                direction,
+                bytes,
+                size,
+                String::new(),
+            ),
+            TransferEvent::Completed {
+                peer_id,
+                id,
+                direction,
+                path,
+            } => {
+                let path = path.to_string_lossy().to_string();
+                (
+                    FfiTransferKind::Completed,
+                    peer_id,
+                    id,
+                    direction,
+                    0,
+                    0,
+                    path,
+                )
+            }
+            TransferEvent::Failed {
+                peer_id,
+                id,
+                direction,
+                reason,
+            } => (
+                FfiTransferKind::Failed,
+                peer_id,
+                id,
+                direction,
+                0,
+                0,
+                reason,
+            ),
+        };
+
+        self.kind = kind;
+        self.incoming = direction == TransferDirection::Incoming;
+        self.id = id;
+        copy_str(&mut self.peer_id, &peer_id.to_base58());
+        self.bytes = bytes;
+        self.size = size;
+        copy_str(&mut self.detail, &detail);
     }
 }
+
+/// Pops the next file transfer event, such as progress or completion.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_next_transfer_event(libp2p_chat_t *ptr, libp2p_chat_transfer_event_t *event);
+/// ```
+///
+/// Progress is coalesced per transfer, so polling now and then is enough to follow it.
+///
+/// Returns `1` if an event is popped, `0` if there are none; otherwise, returns an error code.
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_next_transfer_event(
+    client_ptr: *const FfiClient,
+    event_ptr: *mut FfiTransferEvent,
+) -> i32 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        let event = deref_mut(event_ptr, "event")?;
+
+        let next = client
+            .with_client(
+                |client| Ok(client.next_transfer_event()),
+                |handle| async move { handle.next_transfer_event().await },
+            )
+            .map_err(Failure::from)?;
+        let Some(next) = next else {
+            return Ok(0);
+        };
+        event.fill(next);
+        Ok(1)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Returns a file descriptor that is readable while received messages are waiting, for event loops.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern int libp2p_chat_received_fd(libp2p_chat_t *ptr);
+/// ```
+///
+/// Once it is readable, [`libp2p_chat_receive()`] should be called until it returns `0`; the
+/// descriptor itself must not be read from or closed. It stays the same for the instance, and is
+/// closed by [`libp2p_chat_free()`]. Messages delivered to a callback don't make it readable.
+///
+/// Returns the descriptor (non-negative) on success; otherwise, returns an error code.
+#[cfg(unix)]
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_received_fd(client_ptr: *const FfiClient) -> i32 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        client
+            .with_client(
+                |client| {
+                    client
+                        .received
+                        .readiness_fd()
+                        .map_err(ChatClientError::ReadinessError)
+                },
+                |handle| async move { handle.received_readiness_fd().await },
+            )
+            .map_err(Failure::from)
+    })
+    .unwrap_or_else(|code| code as i32)
+}
+
+/// Returns how many received messages have been dropped because the queue was full.
+///
+/// To be declared in C/C++ as:
+/// ```c
+/// extern uint64_t libp2p_chat_received_dropped(const libp2p_chat_t *ptr);
+/// ```
+///
+/// The queue is bounded by `ChatConfig::received`, so messages must be received regularly.
+/// Returns `0` on error, see [`libp2p_chat_last_error()`].
+#[unsafe(no_mangle)]
+pub extern "C" fn libp2p_chat_received_dropped(client_ptr: *const FfiClient) -> u64 {
+    call(|| {
+        let client = deref(client_ptr, "ptr")?;
+        client
+            .with_client(
+                |client| Ok(client.received.dropped()),
+                |handle| async move { handle.received_dropped().await },
+            )
+            .map_err(Failure::from)
+    })
+    .unwrap_or_default()
+}
@@ -0,0 +1,193 @@
+//! End-to-end latency of received messages.
+//!
+//! Every message carries the time it was published at, see [`Envelope`](crate::message::Envelope),
+//! so the receiver can tell how long it took to propagate through the mesh. This relies on the
+//! clocks of the peers being in sync; messages that seem to arrive before they were sent are
+//! not recorded.
+use libp2p::{PeerId, gossipsub::TopicHash};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in milliseconds.
+const BUCKET_BOUNDS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// A histogram of message latencies.
+///
+/// Each bucket counts the latencies up to its bound (and above the previous one),
+/// with a last bucket for everything above the largest bound.
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKET_BOUNDS_MS.len() + 1],
+            count: 0,
+            sum: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| latency <= Duration::from_millis(*bound))
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Number of recorded latencies.
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the recorded latencies.
+    #[inline]
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Average of the recorded latencies, if any.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.sum.div_f64(self.count as f64))
+    }
+
+    /// Largest recorded latency.
+    #[inline]
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the upper bound of each bucket with its count, `None` being the unbounded last bucket.
+    ///
+    /// Counts are not cumulative.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        BUCKET_BOUNDS_MS
+            .iter()
+            .map(|bound| Some(Duration::from_millis(*bound)))
+            .chain([None])
+            .zip(self.buckets)
+            .collect()
+    }
+
+    /// Estimates the `q`-quantile (within `0.0..=1.0`) as the upper bound of the bucket it falls in.
+    ///
+    /// The unbounded last bucket is estimated with the largest recorded latency.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return Some(bound.map_or(self.max, |bound| bound.min(self.max)));
+            }
+        }
+
+        Some(self.max)
+    }
+}
+
+/// Latency histograms of received messages, per topic and per author.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyStats {
+    pub by_topic: HashMap<TopicHash, LatencyHistogram>,
+    pub by_author: HashMap<PeerId, LatencyHistogram>,
+}
+
+impl LatencyStats {
+    pub(crate) fn record(&mut self, topic: &TopicHash, author: PeerId, latency: Duration) {
+        self.by_topic
+            .entry(topic.clone())
+            .or_default()
+            .record(latency);
+        self.by_author.entry(author).or_default().record(latency);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(latencies_ms: &[u64]) -> LatencyHistogram {
+        let mut histogram = LatencyHistogram::default();
+        for latency in latencies_ms {
+            histogram.record(Duration::from_millis(*latency));
+        }
+        histogram
+    }
+
+    #[test]
+    fn empty() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.quantile(0.5), None);
+        assert!(histogram.buckets().iter().all(|(_, count)| *count == 0));
+    }
+
+    #[test]
+    fn buckets() {
+        let histogram = recorded(&[0, 1, 2, 3, 10_000]);
+        let buckets = histogram.buckets();
+        assert_eq!(buckets.len(), BUCKET_BOUNDS_MS.len() + 1);
+        assert_eq!(buckets[0], (Some(Duration::from_millis(1)), 2));
+        assert_eq!(buckets[1], (Some(Duration::from_millis(2)), 1));
+        assert_eq!(buckets[2], (Some(Duration::from_millis(5)), 1));
+        assert_eq!(buckets[BUCKET_BOUNDS_MS.len()], (None, 1));
+
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), Duration::from_millis(10_006));
+        assert_eq!(histogram.max(), Duration::from_millis(10_000));
+        assert_eq!(histogram.mean(), Some(Duration::from_millis(10_006) / 5));
+    }
+
+    #[test]
+    fn bounds_are_inclusive() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(1_000));
+        histogram.record(Duration::from_micros(1_500));
+        assert_eq!(histogram.buckets()[0].1, 1);
+        assert_eq!(histogram.buckets()[1].1, 1);
+    }
+
+    #[test]
+    fn quantiles() {
+        let histogram = recorded(&[1, 1, 1, 40, 10_000]);
+        assert_eq!(histogram.quantile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_millis(50)));
+        // the unbounded bucket is estimated with the max
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_millis(10_000)));
+        // bounds are capped by the max
+        assert_eq!(recorded(&[3]).quantile(0.5), Some(Duration::from_millis(3)));
+    }
+
+    #[test]
+    fn stats_by_topic_and_author() {
+        let mut stats = LatencyStats::default();
+        let topic = TopicHash::from_raw("chat");
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        stats.record(&topic, alice, Duration::from_millis(1));
+        stats.record(&topic, bob, Duration::from_millis(2));
+        stats.record(&topic, bob, Duration::from_millis(3));
+
+        assert_eq!(stats.by_topic[&topic].count(), 3);
+        assert_eq!(stats.by_author[&alice].count(), 1);
+        assert_eq!(stats.by_author[&bob].count(), 2);
+    }
+}
@@ -1,9 +1,63 @@
+mod bandwidth;
+pub use bandwidth::{BandwidthConfig, TrafficStats};
+
 mod behaviour;
 pub use behaviour::{ChatBehaviour, ChatBehaviourError, ChatBehaviourEvent};
 
 mod client;
 pub use client::{ChatClient, ChatClientError};
 
+mod command;
+pub use command::{ChatCommand, ChatHandle};
+
+mod config;
+pub use config::{ChatConfig, GossipsubConfig, NetworkConfig, PingConfig};
+
+mod latency;
+pub use latency::{LatencyHistogram, LatencyStats};
+
+mod limits;
+pub use limits::{ConnectionLimitsConfig, MemoryLimit};
+
+mod message;
+pub use message::{ChatMessage, Compression};
+
+mod metrics;
+pub use metrics::MetricsConfig;
+
+mod outbox;
+pub use outbox::OutboxConfig;
+
+mod outgoing;
+pub use outgoing::{ChatSender, OutgoingConfig};
+
+mod peer_list;
+pub use peer_list::PeerListConfig;
+
+mod peers;
+pub use peers::PeerInfo;
+
+mod rate_limit;
+pub use rate_limit::RateLimitConfig;
+
+mod received;
+pub use received::{ReceivedConfig, ReceivedOverflow, ReceivedQueue};
+
+mod shutdown;
+pub use shutdown::{ShutdownConfig, ShutdownReport};
+
+mod transfer;
+pub use transfer::{TransferConfig, TransferDirection, TransferEvent, TransferId};
+
+mod version;
+pub use version::{IncompatiblePeer, ProtocolVersion, VersionMismatch};
+
+mod validation;
+pub use validation::{AcceptAll, MessageValidator, ValidationResult};
+
+mod worker;
+pub use worker::{OverflowPolicy, WorkerConfig, WorkerStats};
+
 #[cfg(feature = "ffi")]
 mod external;
 #[cfg(feature = "ffi")]
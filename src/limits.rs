@@ -0,0 +1,105 @@
+//! Limits on the connections of the client, and on the file descriptors of the process.
+//!
+//! Each node dials every peer found over mDNS, so a crowded network can quickly exhaust
+//! the file descriptors (one per connection) or the memory of the process.
+use libp2p::{connection_limits, memory_connection_limits};
+
+/// Soft limit of open files to raise to, unless the hard limit is lower.
+const TARGET_FD_LIMIT: u64 = 65536;
+
+/// Memory usage above which new connections are denied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryLimit {
+    /// Physical memory used by the process, in bytes.
+    Bytes(usize),
+    /// Physical memory used by the process, as a fraction of the total memory (within `0.0..=1.0`).
+    Percentage(f64),
+}
+
+/// Configuration of the connection limits.
+///
+/// Connections over any of the limits are denied; `None` means unlimited.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimitsConfig {
+    /// Maximum number of established connections, incoming & outgoing.
+    pub max_established: Option<u32>,
+    /// Maximum number of established connections with a single peer.
+    pub max_established_per_peer: Option<u32>,
+    /// Maximum number of incoming connections being negotiated.
+    pub max_pending_incoming: Option<u32>,
+    /// Maximum number of outgoing connections being negotiated.
+    pub max_pending_outgoing: Option<u32>,
+    /// Deny new connections while the process uses more memory than this.
+    pub max_memory: Option<MemoryLimit>,
+    /// Try to raise the soft limit of open files (`RLIMIT_NOFILE`) when the client is created.
+    ///
+    /// This changes the whole process, so it is left to binaries rather than libraries.
+    pub raise_fd_limit: bool,
+}
+
+impl Default for ConnectionLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_established: Some(256),
+            // both peers may dial each other at the same time when discovered over mDNS
+            max_established_per_peer: Some(2),
+            max_pending_incoming: Some(64),
+            max_pending_outgoing: Some(64),
+            max_memory: None,
+            raise_fd_limit: false,
+        }
+    }
+}
+
+impl ConnectionLimitsConfig {
+    pub(crate) fn connection_limits(&self) -> connection_limits::ConnectionLimits {
+        connection_limits::ConnectionLimits::default()
+            .with_max_established(self.max_established)
+            .with_max_established_per_peer(self.max_established_per_peer)
+            .with_max_pending_incoming(self.max_pending_incoming)
+            .with_max_pending_outgoing(self.max_pending_outgoing)
+    }
+
+    pub(crate) fn memory_limits(&self) -> Option<memory_connection_limits::Behaviour> {
+        use memory_connection_limits::Behaviour;
+
+        self.max_memory.map(|limit| match limit {
+            MemoryLimit::Bytes(bytes) => Behaviour::with_max_bytes(bytes),
+            MemoryLimit::Percentage(percentage) => Behaviour::with_max_percentage(percentage),
+        })
+    }
+}
+
+/// Raises the soft limit of open files up to 65536, or to the hard limit if it is lower.
+///
+/// Does nothing on platforms without `RLIMIT_NOFILE`.
+#[cfg(unix)]
+pub(crate) fn raise_fd_limit() {
+    use rlimit::Resource;
+
+    if !Resource::NOFILE.is_supported() {
+        return;
+    }
+
+    let (soft, hard) = match Resource::NOFILE.get() {
+        Ok(limits) => limits,
+        Err(err) => {
+            log::warn!("Could not get the open files limit: {err}");
+            return;
+        }
+    };
+
+    // `INFINITY` is the largest value, so this also covers unlimited hard limits
+    let target = hard.min(TARGET_FD_LIMIT);
+    if soft >= target {
+        return;
+    }
+
+    match Resource::NOFILE.set(target, hard) {
+        Ok(()) => log::info!("Raised the open files limit from {soft} to {target}"),
+        Err(err) => log::warn!("Could not raise the open files limit to {target}: {err}"),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn raise_fd_limit() {}
@@ -1,5 +1,7 @@
-use libp2p_rustconnect::ChatClient;
+use libp2p_rustconnect::{ChatClient, ChatConfig, ChatHandle, TrafficStats, TransferEvent};
+use std::collections::BTreeMap;
 use std::env;
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
@@ -19,8 +21,33 @@ async fn main() -> eyre::Result<()> {
         .parse::<u16>()
         .unwrap_or(DEFAULT_PORT);
 
+    // serve metrics if an address is given, e.g. `METRICS_ADDR=127.0.0.1:9090`
+    let mut config = ChatConfig::default();
+    config.metrics.listen_addr = env::var("METRICS_ADDR")
+        .ok()
+        .and_then(|addr| addr.parse().ok());
+    // keep messages typed before any peers are found across restarts
+    config.outbox.path = Some("outbox.bin".into());
+    // keep the peers blocked or allowed with `/block` and `/allow` across restarts
+    config.peer_list.path = Some("peers.txt".into());
+    // each connection takes a file descriptor, and the default soft limit is often 1024
+    config.limits.raise_fd_limit = true;
+
     let cancellation = CancellationToken::new();
-    let (mut client, sender) = ChatClient::new(cancellation.clone())?;
+    let (mut client, sender) = ChatClient::with_config(config, cancellation.clone())?;
+    let handle = client.handle();
+
+    // show the progress of file transfers, which is coalesced so polling now and then is enough
+    let transfers_handle = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(500));
+        loop {
+            interval.tick().await;
+            while let Ok(Some(event)) = transfers_handle.next_transfer_event().await {
+                print_transfer(&event);
+            }
+        }
+    });
 
     // spawn a task to read line and send messages
     let reader_handle = tokio::spawn(async move {
@@ -29,6 +56,10 @@ async fn main() -> eyre::Result<()> {
 
         let mut rl = rustyline::DefaultEditor::new().unwrap();
         println!("Type a message and press ENTER to publish it to the network.");
+        println!("Type '/send <peer> <path>' to send a file to a peer, who must '/allow' you.");
+        println!("Type '/block <peer>' or '/allow <peer>' to block or allow a peer.");
+        println!("Type '/peers' to list the known peers.");
+        println!("Type '/stats' to show the traffic per peer and protocol.");
         println!("Type 'exit' to close the client.");
         while !cancellation.is_cancelled() {
             if let Ok(line) = rl.readline("") {
@@ -40,10 +71,20 @@ async fn main() -> eyre::Result<()> {
                     cancellation.cancel();
                     break;
                 }
-                if sender.send(line).is_err() {
+                if let Some(command) = line.strip_prefix('/') {
+                    handle_command(&handle, command).await;
+                    continue;
+                }
+                let Ok(ack) = sender.send(line).await else {
                     log::error!("Error while sending message to the client");
                     break;
-                }
+                };
+                // messages may wait in the outbox for a while, so don't block the prompt
+                tokio::spawn(async move {
+                    if let Ok(Err(e)) = ack.await {
+                        println!("Could not publish: {e}");
+                    }
+                });
             }
         }
     });
@@ -58,3 +99,132 @@ async fn main() -> eyre::Result<()> {
 
     Ok(())
 }
+
+/// Handles a REPL command, i.e. a line starting with `/`.
+async fn handle_command(handle: &ChatHandle, command: &str) {
+    let args = command.split_whitespace().collect::<Vec<_>>();
+    match args.as_slice() {
+        ["send", peer_id, path] => {
+            let Ok(peer_id) = peer_id.parse() else {
+                println!("Invalid peer id: {peer_id}");
+                return;
+            };
+            match handle.send_file(peer_id, path).await {
+                Ok(id) => println!("Sending {path} to {peer_id} (transfer {id})"),
+                Err(e) => println!("Could not send {path}: {e}"),
+            }
+        }
+        [action @ ("block" | "allow"), peer_id] => {
+            let Ok(peer_id) = peer_id.parse() else {
+                println!("Invalid peer id: {peer_id}");
+                return;
+            };
+            let result = if *action == "block" {
+                handle.block_peer(peer_id).await
+            } else {
+                handle.allow_peer(peer_id).await
+            };
+            match result {
+                Ok(()) => println!("Peer {peer_id} is now {action}ed"),
+                Err(e) => println!("Could not {action} {peer_id}: {e}"),
+            }
+        }
+        ["peers"] => match handle.peers().await {
+            Ok(peers) if peers.is_empty() => println!("No peers yet"),
+            Ok(peers) => {
+                for (peer_id, info) in peers {
+                    let state = if info.is_connected() {
+                        format!("connected ({})", info.connections)
+                    } else {
+                        "disconnected".to_string()
+                    };
+                    let version = info.negotiated_version.map(|v| v.to_string());
+                    let rtt = info.rtt.map(|rtt| format!("{}ms", rtt.as_millis()));
+                    let last_seen = info.last_seen.elapsed().unwrap_or_default().as_secs();
+                    println!(
+                        "{peer_id} {state}, {}, {}, mesh: {}, rtt: {}, last seen {last_seen}s ago",
+                        version.as_deref().unwrap_or("unknown version"),
+                        info.agent_version.as_deref().unwrap_or("unknown agent"),
+                        if info.in_mesh { "yes" } else { "no" },
+                        rtt.as_deref().unwrap_or("unknown"),
+                    );
+                }
+            }
+            Err(e) => println!("Could not get peers: {e}"),
+        },
+        ["stats"] => match handle.bandwidth().await {
+            Ok(stats) if stats.is_empty() => println!("No traffic yet"),
+            Ok(stats) => print_stats(&stats),
+            Err(e) => println!("Could not get stats: {e}"),
+        },
+        _ => println!("Unknown command: /{command}"),
+    }
+}
+
+/// Prints a file transfer event.
+fn print_transfer(event: &TransferEvent) {
+    match event {
+        TransferEvent::Started {
+            peer_id,
+            id,
+            name,
+            size,
+            offset,
+            ..
+        } => println!("Transfer {id} of {name} with {peer_id} started at {offset}/{size} bytes"),
+        TransferEvent::Progress {
+            peer_id,
+            id,
+            bytes,
+            size,
+            ..
+        } => {
+            let percent = if *size == 0 { 100 } else { bytes * 100 / size };
+            println!("Transfer {id} with {peer_id}: {bytes}/{size} bytes ({percent}%)");
+        }
+        TransferEvent::Completed {
+            peer_id, id, path, ..
+        } => println!("Transfer {id} with {peer_id} completed: {}", path.display()),
+        TransferEvent::Failed {
+            peer_id,
+            id,
+            reason,
+            ..
+        } => println!("Transfer {id} with {peer_id} failed: {reason}"),
+    }
+}
+
+/// Prints the traffic in total, per peer and per protocol.
+fn print_stats(stats: &[TrafficStats]) {
+    // (inbound, outbound, inbound rate, outbound rate)
+    type Totals = (u64, u64, f64, f64);
+    fn add(totals: &mut Totals, stat: &TrafficStats) {
+        totals.0 += stat.inbound;
+        totals.1 += stat.outbound;
+        totals.2 += stat.inbound_rate;
+        totals.3 += stat.outbound_rate;
+    }
+    fn line(name: &str, (inbound, outbound, inbound_rate, outbound_rate): &Totals) {
+        println!(
+            "  {name}: in {inbound} B ({inbound_rate:.0} B/s), out {outbound} B ({outbound_rate:.0} B/s)"
+        );
+    }
+
+    let mut total = Totals::default();
+    let mut peers = BTreeMap::<String, Totals>::new();
+    let mut protocols = BTreeMap::<String, Totals>::new();
+    for stat in stats {
+        add(&mut total, stat);
+        add(peers.entry(stat.peer_id.to_string()).or_default(), stat);
+        add(protocols.entry(stat.protocol.clone()).or_default(), stat);
+    }
+
+    println!("Total:");
+    line("all", &total);
+    println!("Per peer:");
+    peers.iter().for_each(|(peer, totals)| line(peer, totals));
+    println!("Per protocol:");
+    protocols
+        .iter()
+        .for_each(|(protocol, totals)| line(protocol, totals));
+}
@@ -0,0 +1,629 @@
+//! Envelope of the messages published over gossipsub.
+//!
+//! Each gossipsub message carries a small header before the payload:
+//!
+//! ```text
+//! | flags (1) | timestamp (8) | [ chunk index (2) | chunk count (2) ] | payload |
+//! ```
+//!
+//! The timestamp is in nanoseconds since the UNIX epoch, and all integers are big-endian.
+//! Payloads that don't fit in a single gossipsub message are split into chunks that share
+//! the same timestamp, and they are reassembled with a [`Reassembler`] on the receiving side.
+//!
+//! Payloads may be compressed before they are split, which is recorded in the flags of every chunk.
+use crate::GossipsubConfig;
+use libp2p::PeerId;
+use libp2p::gossipsub::{MessageId, TopicHash};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Message is a chunk of a larger payload.
+const FLAG_CHUNKED: u8 = 1 << 0;
+/// Payload is compressed with zstd.
+const FLAG_ZSTD: u8 = 1 << 1;
+/// Payload is compressed with snappy.
+const FLAG_SNAPPY: u8 = 1 << 2;
+
+/// Size of the header without the chunk fields.
+const HEADER_SIZE: usize = 1 + 8;
+/// Size of the chunk fields in the header.
+const CHUNK_HEADER_SIZE: usize = 2 + 2;
+/// Room left for gossipsub's own fields (source, sequence number, topic, signature) within a message.
+const GOSSIPSUB_OVERHEAD: usize = 512;
+/// Bytes of a gossipsub message that are not chunk payload, see [`Envelope::max_chunk_size`].
+pub(crate) const MESSAGE_OVERHEAD: usize = GOSSIPSUB_OVERHEAD + HEADER_SIZE + CHUNK_HEADER_SIZE;
+
+/// An error while decoding an [`Envelope`].
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum EnvelopeError {
+    #[error("Message is too short")]
+    TooShort,
+    #[error("Invalid chunk {index} of {count}")]
+    InvalidChunk { index: u16, count: u16 },
+    #[error("Invalid flags: {0:#010b}")]
+    InvalidFlags(u8),
+    #[error("Could not decompress: {0}")]
+    Decompression(String),
+    #[error("Decompressed payload exceeds {0} bytes")]
+    TooLarge(usize),
+}
+
+/// Compression algorithm applied to a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Snappy,
+}
+
+impl Compression {
+    pub(crate) fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Self::Snappy => snap::raw::Encoder::new()
+                .compress_vec(data)
+                .map_err(io::Error::other),
+        }
+    }
+
+    /// Decompresses the data, failing if the result would exceed `max_size` bytes.
+    ///
+    /// The limit is checked before (snappy) or while (zstd) decompressing,
+    /// so that a small message can't blow up in memory.
+    pub(crate) fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>, EnvelopeError> {
+        let decompression = |err: io::Error| EnvelopeError::Decompression(err.to_string());
+
+        match self {
+            Self::Zstd => {
+                let mut payload = Vec::new();
+                zstd::stream::read::Decoder::new(data)
+                    .map_err(decompression)?
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut payload)
+                    .map_err(decompression)?;
+                if payload.len() > max_size {
+                    return Err(EnvelopeError::TooLarge(max_size));
+                }
+                Ok(payload)
+            }
+            Self::Snappy => {
+                let len = snap::raw::decompress_len(data)
+                    .map_err(|err| EnvelopeError::Decompression(err.to_string()))?;
+                if len > max_size {
+                    return Err(EnvelopeError::TooLarge(max_size));
+                }
+                snap::raw::Decoder::new()
+                    .decompress_vec(data)
+                    .map_err(|err| EnvelopeError::Decompression(err.to_string()))
+            }
+        }
+    }
+}
+
+/// A message received from the network, and accepted by the validator.
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    /// Peer that published the message.
+    pub author: PeerId,
+    /// Topic the message was published to.
+    pub topic: TopicHash,
+    /// The message itself.
+    pub data: String,
+    /// When the message was published, according to the author's clock.
+    pub sent_at: SystemTime,
+    /// When the message was received, i.e. its last chunk arrived.
+    pub received_at: SystemTime,
+    /// Time between publishing and receiving, `None` if the clocks are too far apart to tell.
+    pub latency: Option<Duration>,
+}
+
+impl ChatMessage {
+    pub(crate) fn new(
+        author: PeerId,
+        topic: TopicHash,
+        data: String,
+        timestamp: u64,
+        received_at: SystemTime,
+    ) -> Self {
+        let sent_at = UNIX_EPOCH + Duration::from_nanos(timestamp);
+        Self {
+            author,
+            topic,
+            data,
+            sent_at,
+            received_at,
+            latency: received_at.duration_since(sent_at).ok(),
+        }
+    }
+}
+
+/// Position of a chunk within its payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Chunk {
+    pub(crate) index: u16,
+    pub(crate) count: u16,
+}
+
+/// A decoded gossipsub message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Envelope {
+    /// Time of publishing, in nanoseconds since the UNIX epoch.
+    pub(crate) timestamp: u64,
+    /// Set if the payload is a chunk of a larger one.
+    pub(crate) chunk: Option<Chunk>,
+    /// Set if the (whole) payload is compressed.
+    pub(crate) compression: Option<Compression>,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Splits the payload into envelopes with at most `chunk_size` bytes of payload each.
+    ///
+    /// Returns a single un-chunked envelope if the payload fits, or `None` if it takes more
+    /// chunks than can be counted.
+    pub(crate) fn split(
+        timestamp: u64,
+        compression: Option<Compression>,
+        payload: &[u8],
+        chunk_size: usize,
+    ) -> Option<Vec<Self>> {
+        if payload.len() <= chunk_size {
+            return Some(vec![Self {
+                timestamp,
+                chunk: None,
+                compression,
+                payload: payload.to_vec(),
+            }]);
+        }
+
+        let count = u16::try_from(payload.len().div_ceil(chunk_size)).ok()?;
+        let envelopes = payload
+            .chunks(chunk_size)
+            .zip(0..count)
+            .map(|(payload, index)| Self {
+                timestamp,
+                chunk: Some(Chunk { index, count }),
+                compression,
+                payload: payload.to_vec(),
+            })
+            .collect();
+        Some(envelopes)
+    }
+
+    /// Largest chunk payload that fits in a gossipsub message of `max_transmit_size` bytes,
+    /// which must be larger than [`MESSAGE_OVERHEAD`].
+    pub(crate) fn max_chunk_size(max_transmit_size: usize) -> usize {
+        max_transmit_size.saturating_sub(MESSAGE_OVERHEAD).max(1)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + CHUNK_HEADER_SIZE + self.payload.len());
+
+        let mut flags = 0;
+        if self.chunk.is_some() {
+            flags |= FLAG_CHUNKED;
+        }
+        match self.compression {
+            Some(Compression::Zstd) => flags |= FLAG_ZSTD,
+            Some(Compression::Snappy) => flags |= FLAG_SNAPPY,
+            None => {}
+        }
+        data.push(flags);
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        if let Some(chunk) = self.chunk {
+            data.extend_from_slice(&chunk.index.to_be_bytes());
+            data.extend_from_slice(&chunk.count.to_be_bytes());
+        }
+        data.extend_from_slice(&self.payload);
+
+        data
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, EnvelopeError> {
+        if data.len() < HEADER_SIZE {
+            return Err(EnvelopeError::TooShort);
+        }
+
+        let flags = data[0];
+        let timestamp =
+            u64::from_be_bytes(data[1..HEADER_SIZE].try_into().expect("should be 8 bytes"));
+        let mut rest = &data[HEADER_SIZE..];
+
+        let compression = match (flags & FLAG_ZSTD != 0, flags & FLAG_SNAPPY != 0) {
+            (false, false) => None,
+            (true, false) => Some(Compression::Zstd),
+            (false, true) => Some(Compression::Snappy),
+            (true, true) => return Err(EnvelopeError::InvalidFlags(flags)),
+        };
+
+        let chunk = if flags & FLAG_CHUNKED != 0 {
+            if rest.len() < CHUNK_HEADER_SIZE {
+                return Err(EnvelopeError::TooShort);
+            }
+            let index = u16::from_be_bytes([rest[0], rest[1]]);
+            let count = u16::from_be_bytes([rest[2], rest[3]]);
+            if index >= count {
+                return Err(EnvelopeError::InvalidChunk { index, count });
+            }
+            rest = &rest[CHUNK_HEADER_SIZE..];
+            Some(Chunk { index, count })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            timestamp,
+            chunk,
+            compression,
+            payload: rest.to_vec(),
+        })
+    }
+}
+
+/// A received gossipsub message id, paired with the peer that propagated it.
+///
+/// Needed to report the validation result of the message back to gossipsub.
+pub(crate) type Delivery = (MessageId, PeerId);
+
+/// Outcome of adding a chunk to the [`Reassembler`].
+pub(crate) enum Reassembly {
+    /// More chunks are needed.
+    Pending,
+    /// The chunk is a duplicate or inconsistent with its set, or there are too many sets
+    /// pending already, and is dropped.
+    Dropped,
+    /// This was the last missing chunk, here is the whole payload.
+    Complete {
+        payload: Vec<u8>,
+        deliveries: Vec<Delivery>,
+    },
+    /// The set has grown (or claims to grow) beyond the maximum size, and is discarded.
+    Discarded { deliveries: Vec<Delivery> },
+}
+
+/// A payload with some of its chunks received.
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    deliveries: Vec<Delivery>,
+    missing: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Collects chunks of payloads until they are complete.
+///
+/// Chunks are grouped by their author & timestamp; sets that are not completed
+/// within the timeout, or that grow beyond the maximum size, are discarded.
+/// The number of incomplete sets is bounded per author and in total, so that
+/// peers can't exhaust memory by starting sets they never finish.
+pub(crate) struct Reassembler {
+    timeout: Duration,
+    max_size: usize,
+    /// Largest chunk count a set within `max_size` can have.
+    max_count: usize,
+    max_partials: usize,
+    max_partials_per_author: usize,
+    partials: HashMap<(PeerId, u64), Partial>,
+}
+
+impl Reassembler {
+    pub(crate) fn new(config: &GossipsubConfig) -> Self {
+        let chunk_size = Envelope::max_chunk_size(config.max_transmit_size);
+        Self {
+            timeout: config.chunk_timeout,
+            max_size: config.max_message_size,
+            max_count: config.max_message_size.div_ceil(chunk_size),
+            max_partials: config.max_partial_messages,
+            max_partials_per_author: config.max_partial_messages_per_author,
+            partials: Default::default(),
+        }
+    }
+
+    /// How often [`Reassembler::prune`] should be called, so that sets are discarded soon after
+    /// they time out.
+    pub(crate) fn prune_interval(&self) -> Duration {
+        (self.timeout / 2).max(Duration::from_millis(10))
+    }
+
+    /// Adds a chunk that has been delivered with the given message.
+    pub(crate) fn insert(
+        &mut self,
+        author: PeerId,
+        timestamp: u64,
+        chunk: Chunk,
+        payload: Vec<u8>,
+        delivery: Delivery,
+    ) -> Reassembly {
+        let key = (author, timestamp);
+        if chunk.count as usize > self.max_count {
+            log::warn!(
+                "Discarding chunked message from {author}, {} chunks exceed {} bytes",
+                chunk.count,
+                self.max_size
+            );
+            return match self.partials.remove(&key) {
+                Some(partial) => {
+                    let mut deliveries = partial.deliveries;
+                    deliveries.push(delivery);
+                    Reassembly::Discarded { deliveries }
+                }
+                None => Reassembly::Discarded {
+                    deliveries: vec![delivery],
+                },
+            };
+        }
+        if !self.partials.contains_key(&key) {
+            let by_author = self.partials.keys().filter(|(a, _)| *a == author).count();
+            if by_author >= self.max_partials_per_author || self.partials.len() >= self.max_partials
+            {
+                log::warn!("Dropping chunk from {author}, too many chunked messages pending");
+                return Reassembly::Dropped;
+            }
+        }
+
+        let partial = self.partials.entry(key).or_insert_with(|| Partial {
+            chunks: vec![None; chunk.count as usize],
+            deliveries: Vec::with_capacity(chunk.count as usize),
+            missing: chunk.count as usize,
+            size: 0,
+            started: Instant::now(),
+        });
+
+        // chunk count must be consistent within the set, and duplicates are ignored
+        if partial.chunks.len() != chunk.count as usize {
+            log::warn!("Inconsistent chunk count {} from {author}", chunk.count);
+            return Reassembly::Dropped;
+        }
+        let slot = &mut partial.chunks[chunk.index as usize];
+        if slot.is_some() {
+            return Reassembly::Dropped;
+        }
+
+        partial.size += payload.len();
+        partial.missing -= 1;
+        partial.deliveries.push(delivery);
+        *slot = Some(payload);
+
+        if partial.size > self.max_size {
+            log::warn!(
+                "Discarding chunked message from {author}, exceeds {} bytes",
+                self.max_size
+            );
+            let partial = self.partials.remove(&key).expect("should exist");
+            return Reassembly::Discarded {
+                deliveries: partial.deliveries,
+            };
+        }
+
+        if partial.missing > 0 {
+            return Reassembly::Pending;
+        }
+
+        let partial = self.partials.remove(&key).expect("should exist");
+        Reassembly::Complete {
+            payload: partial.chunks.into_iter().flatten().flatten().collect(),
+            deliveries: partial.deliveries,
+        }
+    }
+
+    /// Discards the incomplete sets that have timed out, and returns their deliveries.
+    ///
+    /// Meant to be called every [`Reassembler::prune_interval`].
+    pub(crate) fn prune(&mut self) -> Vec<Delivery> {
+        let timeout = self.timeout;
+        let mut expired = Vec::new();
+        self.partials.retain(|(author, _), partial| {
+            let alive = partial.started.elapsed() < timeout;
+            if !alive {
+                log::warn!(
+                    "Discarding chunked message from {author}, {} chunks missing",
+                    partial.missing
+                );
+                expired.append(&mut partial.deliveries);
+            }
+            alive
+        });
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> GossipsubConfig {
+        GossipsubConfig {
+            max_transmit_size: 1024 + GOSSIPSUB_OVERHEAD + HEADER_SIZE + CHUNK_HEADER_SIZE,
+            max_message_size: 4 * 1024,
+            chunk_timeout: Duration::from_secs(30),
+            max_partial_messages: 3,
+            max_partial_messages_per_author: 2,
+            ..Default::default()
+        }
+    }
+
+    fn delivery(n: u8) -> Delivery {
+        (MessageId::new(&[n]), PeerId::random())
+    }
+
+    fn chunk(index: u16, count: u16) -> Chunk {
+        Chunk { index, count }
+    }
+
+    #[test]
+    fn envelope_roundtrip() {
+        let envelope = Envelope {
+            timestamp: 42,
+            chunk: None,
+            compression: Some(Compression::Snappy),
+            payload: b"hello".to_vec(),
+        };
+        assert_eq!(Envelope::decode(&envelope.encode()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn split_and_decode() {
+        let payload = (0..2500).map(|i| i as u8).collect::<Vec<_>>();
+        let envelopes = Envelope::split(7, None, &payload, 1000).unwrap();
+        assert_eq!(envelopes.len(), 3);
+
+        let mut joined = Vec::new();
+        for (index, envelope) in envelopes.iter().enumerate() {
+            let decoded = Envelope::decode(&envelope.encode()).unwrap();
+            assert_eq!(decoded.timestamp, 7);
+            assert_eq!(decoded.chunk, Some(chunk(index as u16, 3)));
+            joined.extend(decoded.payload);
+        }
+        assert_eq!(joined, payload);
+
+        let single = Envelope::split(7, None, &payload, payload.len()).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].chunk, None);
+    }
+
+    #[test]
+    fn split_too_many_chunks() {
+        let payload = vec![0; u16::MAX as usize + 1];
+        assert!(Envelope::split(7, None, &payload, 1).is_none());
+
+        let envelopes = Envelope::split(7, None, &payload[1..], 1).unwrap();
+        assert_eq!(envelopes.len(), u16::MAX as usize);
+        assert_eq!(
+            envelopes.last().unwrap().chunk,
+            Some(chunk(u16::MAX - 1, u16::MAX))
+        );
+    }
+
+    #[test]
+    fn decompress_within_limit() {
+        let payload = vec![b'a'; 10_000];
+        for compression in [Compression::Zstd, Compression::Snappy] {
+            let compressed = compression.compress(&payload).unwrap();
+            assert!(compressed.len() < 1000, "{compression:?}");
+            assert_eq!(
+                compression.decompress(&compressed, 10_000).unwrap(),
+                payload
+            );
+            assert!(
+                matches!(
+                    compression.decompress(&compressed, 9_999),
+                    Err(EnvelopeError::TooLarge(9_999))
+                ),
+                "{compression:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(matches!(
+            Envelope::decode(&[0; HEADER_SIZE - 1]),
+            Err(EnvelopeError::TooShort)
+        ));
+
+        let mut data = vec![FLAG_ZSTD | FLAG_SNAPPY];
+        data.extend_from_slice(&0u64.to_be_bytes());
+        assert!(matches!(
+            Envelope::decode(&data),
+            Err(EnvelopeError::InvalidFlags(_))
+        ));
+
+        let mut data = vec![FLAG_CHUNKED];
+        data.extend_from_slice(&0u64.to_be_bytes());
+        assert!(matches!(
+            Envelope::decode(&data),
+            Err(EnvelopeError::TooShort)
+        ));
+        data.extend_from_slice(&[0, 2, 0, 2]);
+        assert!(matches!(
+            Envelope::decode(&data),
+            Err(EnvelopeError::InvalidChunk { index: 2, count: 2 })
+        ));
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let mut reassembler = Reassembler::new(&config());
+        let author = PeerId::random();
+
+        let pending = reassembler.insert(author, 1, chunk(1, 2), b"world".to_vec(), delivery(1));
+        assert!(matches!(pending, Reassembly::Pending));
+        let duplicate = reassembler.insert(author, 1, chunk(1, 2), b"world".to_vec(), delivery(2));
+        assert!(matches!(duplicate, Reassembly::Dropped));
+        let inconsistent = reassembler.insert(author, 1, chunk(0, 3), b"x".to_vec(), delivery(3));
+        assert!(matches!(inconsistent, Reassembly::Dropped));
+
+        match reassembler.insert(author, 1, chunk(0, 2), b"hello ".to_vec(), delivery(4)) {
+            Reassembly::Complete {
+                payload,
+                deliveries,
+            } => {
+                assert_eq!(payload, b"hello world");
+                assert_eq!(deliveries.len(), 2);
+            }
+            _ => panic!("should be complete"),
+        }
+        assert!(reassembler.partials.is_empty());
+    }
+
+    #[test]
+    fn reassemble_too_large() {
+        let mut reassembler = Reassembler::new(&config());
+        let author = PeerId::random();
+
+        // 4 KiB in chunks of 1 KiB allows at most 4 chunks
+        let too_many = reassembler.insert(author, 1, chunk(0, 5), vec![0; 10], delivery(1));
+        assert!(matches!(too_many, Reassembly::Discarded { deliveries } if deliveries.len() == 1));
+
+        for index in 0..3 {
+            let pending =
+                reassembler.insert(author, 2, chunk(index, 4), vec![0; 1024], delivery(2));
+            assert!(matches!(pending, Reassembly::Pending));
+        }
+        let oversized = reassembler.insert(author, 2, chunk(3, 4), vec![0; 1025], delivery(3));
+        assert!(matches!(oversized, Reassembly::Discarded { deliveries } if deliveries.len() == 4));
+        assert!(reassembler.partials.is_empty());
+    }
+
+    #[test]
+    fn reassemble_limits_pending() {
+        let mut reassembler = Reassembler::new(&config());
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+
+        for timestamp in 0..2 {
+            let pending = reassembler.insert(alice, timestamp, chunk(0, 2), vec![0], delivery(1));
+            assert!(matches!(pending, Reassembly::Pending));
+        }
+        let per_author = reassembler.insert(alice, 2, chunk(0, 2), vec![0], delivery(2));
+        assert!(matches!(per_author, Reassembly::Dropped));
+
+        // chunks of pending sets are still accepted
+        let pending = reassembler.insert(alice, 0, chunk(1, 2), vec![0], delivery(3));
+        assert!(matches!(pending, Reassembly::Complete { .. }));
+
+        let pending = reassembler.insert(bob, 0, chunk(0, 2), vec![0], delivery(4));
+        assert!(matches!(pending, Reassembly::Pending));
+        let pending = reassembler.insert(bob, 1, chunk(0, 2), vec![0], delivery(5));
+        assert!(matches!(pending, Reassembly::Pending));
+        let total = reassembler.insert(PeerId::random(), 0, chunk(0, 2), vec![0], delivery(6));
+        assert!(matches!(total, Reassembly::Dropped));
+    }
+
+    #[test]
+    fn prune_timed_out() {
+        let mut reassembler = Reassembler::new(&GossipsubConfig {
+            chunk_timeout: Duration::from_millis(10),
+            ..config()
+        });
+        let author = PeerId::random();
+
+        let pending = reassembler.insert(author, 1, chunk(0, 2), vec![0], delivery(1));
+        assert!(matches!(pending, Reassembly::Pending));
+        assert!(reassembler.prune().is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(reassembler.prune().len(), 1);
+        assert!(reassembler.partials.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+//! Prometheus metrics of the client, and an optional HTTP endpoint to scrape them.
+//!
+//! Metrics of the libp2p protocols (connections, gossipsub mesh, identify & ping) are
+//! collected by [`libp2p::metrics`], and the client adds its own counters on top.
+use libp2p::metrics::{Metrics, Recorder};
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, text};
+use prometheus_client::metrics::{counter::Counter, family::Family, gauge::Gauge};
+use prometheus_client::registry::Registry;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+/// Configuration of the metrics endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsConfig {
+    /// Address to serve the metrics on, at `http://{listen_addr}/metrics`.
+    ///
+    /// Metrics are collected either way, and can be read with [`ChatClient::metrics`](crate::ChatClient::metrics).
+    pub listen_addr: Option<SocketAddr>,
+}
+
+/// Why a received message was rejected.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub(crate) enum RejectReason {
+    /// Envelope could not be decoded.
+    Invalid,
+    /// Chunks grew beyond the maximum message size.
+    TooLarge,
+    /// Payload could not be decompressed.
+    Decompression,
+    /// Author is over the rate limit.
+    RateLimit,
+    /// Rejected by the [`MessageValidator`](crate::MessageValidator).
+    Validator,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RejectLabels {
+    reason: RejectReason,
+}
+
+/// Counters of the client, along with the libp2p metrics.
+pub(crate) struct ChatMetrics {
+    registry: Arc<Registry>,
+    libp2p: Metrics,
+    published: Counter,
+    publish_errors: Counter,
+    received: Counter,
+    rejected: Family<RejectLabels, Counter>,
+    /// Shared with the [`WorkerPool`](crate::worker::WorkerPool).
+    pub(crate) worker_queue_depth: Gauge,
+    pub(crate) worker_dropped: Counter,
+    /// Shared with the [`ReceivedQueue`](crate::ReceivedQueue).
+    pub(crate) received_dropped: Counter,
+}
+
+impl ChatMetrics {
+    /// Registers the client metrics in a registry that already holds the behaviour metrics.
+    pub(crate) fn new(mut registry: Registry) -> Self {
+        let libp2p = Metrics::new(&mut registry);
+
+        let chat = registry.sub_registry_with_prefix("chat");
+        let published = Counter::default();
+        chat.register(
+            "messages_published",
+            "Number of messages published",
+            published.clone(),
+        );
+        let publish_errors = Counter::default();
+        chat.register(
+            "publish_errors",
+            "Number of messages that could not be published",
+            publish_errors.clone(),
+        );
+        let received = Counter::default();
+        chat.register(
+            "messages_received",
+            "Number of messages received and accepted",
+            received.clone(),
+        );
+        let rejected = Family::default();
+        chat.register(
+            "messages_rejected",
+            "Number of received messages rejected, by reason",
+            rejected.clone(),
+        );
+
+        let worker_queue_depth = Gauge::default();
+        chat.register(
+            "worker_queue_depth",
+            "Number of received messages waiting for a worker",
+            worker_queue_depth.clone(),
+        );
+        let worker_dropped = Counter::default();
+        chat.register(
+            "worker_dropped",
+            "Number of received messages dropped because the worker queue was full",
+            worker_dropped.clone(),
+        );
+
+        let received_dropped = Counter::default();
+        chat.register(
+            "received_dropped",
+            "Number of accepted messages dropped because the received queue was full",
+            received_dropped.clone(),
+        );
+
+        Self {
+            registry: Arc::new(registry),
+            libp2p,
+            published,
+            publish_errors,
+            received,
+            rejected,
+            worker_queue_depth,
+            worker_dropped,
+            received_dropped,
+        }
+    }
+
+    /// Records an event with the libp2p metrics.
+    #[inline]
+    pub(crate) fn record<E>(&self, event: &E)
+    where
+        Metrics: Recorder<E>,
+    {
+        self.libp2p.record(event);
+    }
+
+    #[inline]
+    pub(crate) fn published(&self) {
+        self.published.inc();
+    }
+
+    #[inline]
+    pub(crate) fn publish_error(&self) {
+        self.publish_errors.inc();
+    }
+
+    #[inline]
+    pub(crate) fn received(&self) {
+        self.received.inc();
+    }
+
+    #[inline]
+    pub(crate) fn rejected(&self, reason: RejectReason) {
+        self.rejected.get_or_create(&RejectLabels { reason }).inc();
+    }
+
+    /// Encodes all metrics in the OpenMetrics text format.
+    pub(crate) fn encode(&self) -> String {
+        encode(&self.registry)
+    }
+
+    /// Serves the metrics over HTTP until cancelled.
+    pub(crate) fn serve(
+        &self,
+        listen_addr: SocketAddr,
+        cancellation: CancellationToken,
+    ) -> std::io::Result<()> {
+        let listener = std::net::TcpListener::bind(listen_addr)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        log::info!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cancellation.cancelled() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            tokio::spawn(respond(stream, registry.clone()));
+                        }
+                        Err(err) => log::warn!("Could not accept metrics connection: {err}"),
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn encode(registry: &Registry) -> String {
+    let mut buf = String::new();
+    text::encode(&mut buf, registry).expect("writing to a string should not fail");
+    buf
+}
+
+/// Answers a single HTTP request, only `GET /metrics` is supported.
+async fn respond(mut stream: TcpStream, registry: Arc<Registry>) {
+    // we only care about the request line, so the first read is enough
+    let mut buf = [0u8; 1024];
+    let len = match stream.read(&mut buf).await {
+        Ok(len) => len,
+        Err(err) => {
+            log::debug!("Could not read metrics request: {err}");
+            return;
+        }
+    };
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = encode(&registry);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        log::debug!("Could not write metrics response: {err}");
+    }
+    let _ = stream.shutdown().await;
+}
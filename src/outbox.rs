@@ -0,0 +1,272 @@
+//! Messages that could not be published yet, since we have no peers on the chat topic.
+//!
+//! Right after startup it takes a while to discover peers over mDNS, and gossipsub refuses to
+//! publish with `InsufficientPeers` in the meantime. Such messages are kept here, and published
+//! once a peer subscribes to the topic, unless they expire first.
+//!
+//! The outbox can be persisted to a file, so that the messages survive a restart. Each message
+//! is stored as its timestamp (nanoseconds, 8 bytes), the length of its data (4 bytes) and the
+//! data itself, all big-endian.
+use crate::ChatClientError;
+use libp2p::gossipsub::MessageId;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+/// Configuration of the outbox.
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Maximum number of messages kept in the outbox; `0` disables it.
+    ///
+    /// Messages that don't fit fail with `InsufficientPeers` right away.
+    pub capacity: usize,
+    /// How long a message is kept, since it was first published.
+    pub ttl: Duration,
+    /// Time between two attempts to publish the messages, on top of the attempt made
+    /// whenever a peer subscribes to the topic.
+    pub retry_interval: Duration,
+    /// File to load the outbox from and save it to, if any.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            ttl: Duration::from_secs(60),
+            retry_interval: Duration::from_secs(1),
+            path: None,
+        }
+    }
+}
+
+/// Sender of the result of a publish, back to the [`ChatSender`](crate::ChatSender).
+pub(crate) type Ack = oneshot::Sender<Result<MessageId, ChatClientError>>;
+
+/// A message waiting in the outbox.
+pub(crate) struct Pending {
+    /// When the message was first published, in nanoseconds since the Unix epoch.
+    pub(crate) timestamp: u64,
+    pub(crate) data: Vec<u8>,
+    /// Not persisted, so messages loaded from the file are not acknowledged.
+    pub(crate) ack: Option<Ack>,
+}
+
+pub(crate) struct Outbox {
+    config: OutboxConfig,
+    messages: VecDeque<Pending>,
+    /// Whether the messages changed since they were last saved.
+    dirty: bool,
+}
+
+impl Outbox {
+    /// Loads the outbox from the configured file, or starts empty if there is no such file.
+    pub(crate) fn load(config: &OutboxConfig) -> io::Result<Self> {
+        let mut outbox = Self {
+            config: config.clone(),
+            messages: VecDeque::new(),
+            dirty: false,
+        };
+
+        let content = match &config.path {
+            Some(path) => match fs::read(path) {
+                Ok(content) => content,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(outbox),
+                Err(err) => return Err(err),
+            },
+            None => return Ok(outbox),
+        };
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated outbox");
+        let mut rest = content.as_slice();
+        while !rest.is_empty() {
+            let (header, tail) = rest.split_at_checked(12).ok_or_else(invalid)?;
+            let timestamp = u64::from_be_bytes(header[..8].try_into().expect("should be 8 bytes"));
+            let len = u32::from_be_bytes(header[8..].try_into().expect("should be 4 bytes"));
+            let (data, tail) = tail.split_at_checked(len as usize).ok_or_else(invalid)?;
+            outbox.messages.push_back(Pending {
+                timestamp,
+                data: data.to_vec(),
+                ack: None,
+            });
+            rest = tail;
+        }
+
+        if !outbox.messages.is_empty() {
+            log::info!("Loaded {} messages into the outbox", outbox.messages.len());
+        }
+        Ok(outbox)
+    }
+
+    /// Saves the outbox to the configured file, if any, and if it changed since the last save.
+    pub(crate) fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.config.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut content = Vec::new();
+        for message in &self.messages {
+            content.extend_from_slice(&message.timestamp.to_be_bytes());
+            content.extend_from_slice(&(message.data.len() as u32).to_be_bytes());
+            content.extend_from_slice(&message.data);
+        }
+        fs::write(path, content)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Returns whether a message can be added.
+    #[inline]
+    pub(crate) fn has_room(&self) -> bool {
+        self.messages.len() < self.config.capacity
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Adds a message to the back of the outbox, regardless of its capacity.
+    pub(crate) fn push(&mut self, message: Pending) {
+        self.messages.push_back(message);
+        self.dirty = true;
+    }
+
+    /// Sets the acknowledgement of the most recently added message.
+    pub(crate) fn set_last_ack(&mut self, ack: Ack) {
+        if let Some(message) = self.messages.back_mut() {
+            message.ack = Some(ack);
+        }
+    }
+
+    /// Drops the acknowledgements of all messages, e.g. once the client is stopped.
+    pub(crate) fn drop_acks(&mut self) {
+        for message in &mut self.messages {
+            message.ack = None;
+        }
+    }
+
+    /// Returns the oldest message, to be published first.
+    #[inline]
+    pub(crate) fn front(&self) -> Option<&Pending> {
+        self.messages.front()
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<Pending> {
+        let message = self.messages.pop_front();
+        self.dirty |= message.is_some();
+        message
+    }
+
+    /// Removes & returns the messages older than the TTL.
+    pub(crate) fn expire(&mut self) -> Vec<Pending> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let ttl = self.config.ttl.as_nanos() as u64;
+
+        let (expired, kept) = std::mem::take(&mut self.messages)
+            .into_iter()
+            .partition::<Vec<_>, _>(|message| now.saturating_sub(message.timestamp) > ttl);
+        self.messages = kept.into();
+        self.dirty |= !expired.is_empty();
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(timestamp: u64, data: &[u8]) -> Pending {
+        Pending {
+            timestamp,
+            data: data.to_vec(),
+            ack: None,
+        }
+    }
+
+    fn config(name: &str) -> OutboxConfig {
+        let path = std::env::temp_dir().join(format!("{name}-{}.bin", std::process::id()));
+        OutboxConfig {
+            path: Some(path),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let config = config("outbox");
+        let mut outbox = Outbox::load(&config).unwrap();
+        assert!(outbox.is_empty());
+        outbox.push(pending(1, b"hello"));
+        outbox.push(pending(2, b""));
+        outbox.push(pending(u64::MAX, &[0xff; 300]));
+        outbox.save().unwrap();
+
+        let mut loaded = Outbox::load(&config).unwrap();
+        let path = config.path.unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 3);
+        for (timestamp, data) in [(1, &b"hello"[..]), (2, b""), (u64::MAX, &[0xff; 300])] {
+            let message = loaded.pop_front().unwrap();
+            assert_eq!(message.timestamp, timestamp);
+            assert_eq!(message.data, data);
+            assert!(message.ack.is_none());
+        }
+
+        // unchanged outboxes are not written
+        let mut outbox = Outbox::load(&OutboxConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        outbox.save().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn load_truncated() {
+        let config = config("outbox-truncated");
+        let path = config.path.clone().unwrap();
+        let mut content = 1u64.to_be_bytes().to_vec();
+        content.extend_from_slice(&10u32.to_be_bytes());
+        content.extend_from_slice(b"short");
+        fs::write(&path, content).unwrap();
+
+        let err = Outbox::load(&config).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn expire() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let mut outbox = Outbox::load(&OutboxConfig::default()).unwrap();
+        outbox.push(pending(
+            now - Duration::from_secs(120).as_nanos() as u64,
+            b"old",
+        ));
+        outbox.push(pending(now, b"new"));
+
+        let expired = outbox.expire();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].data, b"old");
+        assert_eq!(outbox.front().unwrap().data, b"new");
+    }
+}
@@ -0,0 +1,91 @@
+//! The bounded channel of messages to be published by the client.
+//!
+//! Each message is acknowledged once the client has published it, so that senders learn about
+//! failures (e.g. when there are no peers), and can't queue more than the client keeps up with.
+use crate::ChatClientError;
+use libp2p::gossipsub::MessageId;
+use tokio::sync::{mpsc, oneshot};
+
+/// Configuration of the outgoing channel.
+#[derive(Debug, Clone)]
+pub struct OutgoingConfig {
+    /// Maximum number of messages waiting to be published; senders wait for room beyond that.
+    pub capacity: usize,
+}
+
+impl Default for OutgoingConfig {
+    fn default() -> Self {
+        Self { capacity: 64 }
+    }
+}
+
+/// A message waiting to be published, with the sender of its acknowledgement.
+pub(crate) struct Outgoing {
+    pub(crate) data: Vec<u8>,
+    pub(crate) ack: oneshot::Sender<Result<MessageId, ChatClientError>>,
+}
+
+/// A cloneable sender of messages to a running [`ChatClient`](crate::ChatClient).
+///
+/// Obtained along with the client from [`ChatClient::new`](crate::ChatClient::new).
+#[derive(Debug, Clone)]
+pub struct ChatSender {
+    pub(crate) messages: mpsc::Sender<Outgoing>,
+}
+
+impl ChatSender {
+    pub(crate) fn channel(config: &OutgoingConfig) -> (Self, mpsc::Receiver<Outgoing>) {
+        let (messages, receiver) = mpsc::channel(config.capacity.max(1));
+        (Self { messages }, receiver)
+    }
+
+    /// Publishes a message, and returns its id once it is published.
+    ///
+    /// Waits for room in the channel first, if the client is falling behind.
+    /// See [`ChatClient::publish`](crate::ChatClient::publish) for the errors.
+    ///
+    /// Messages kept in the outbox are only acknowledged once they are published, or expire;
+    /// see [`Self::send`] to not wait for that.
+    pub async fn publish(&self, data: impl Into<Vec<u8>>) -> Result<MessageId, ChatClientError> {
+        let receiver = self.send(data).await?;
+        receiver.await.map_err(|_| ChatClientError::ClientStopped)?
+    }
+
+    /// Queues a message to be published, and returns a receiver for its acknowledgement.
+    ///
+    /// Waits for room in the channel, if the client is falling behind.
+    pub async fn send(
+        &self,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<oneshot::Receiver<Result<MessageId, ChatClientError>>, ChatClientError> {
+        let (ack, receiver) = oneshot::channel();
+        let message = Outgoing {
+            data: data.into(),
+            ack,
+        };
+        self.messages
+            .send(message)
+            .await
+            .map_err(|_| ChatClientError::ClientStopped)?;
+        Ok(receiver)
+    }
+
+    /// Same as [`Self::publish`], for synchronous code outside of the async runtime.
+    ///
+    /// # Panics
+    ///
+    /// If called within an async execution context.
+    pub fn blocking_publish(&self, data: impl Into<Vec<u8>>) -> Result<MessageId, ChatClientError> {
+        let (ack, receiver) = oneshot::channel();
+        let message = Outgoing {
+            data: data.into(),
+            ack,
+        };
+        self.messages
+            .blocking_send(message)
+            .map_err(|_| ChatClientError::ClientStopped)?;
+        receiver
+            .blocking_recv()
+            .map_err(|_| ChatClientError::ClientStopped)?
+    }
+}
@@ -0,0 +1,168 @@
+//! Allow & block lists of peers, persisted to a file.
+//!
+//! The lists are enforced at the connection level by [`allow_block_list`](libp2p::allow_block_list)
+//! behaviours within [`ChatBehaviour`](crate::ChatBehaviour); this module only keeps them on disk.
+//! The file has one peer per line, prefixed by its list:
+//!
+//! ```text
+//! allow 12D3KooW...
+//! block 12D3KooW...
+//! ```
+use libp2p::PeerId;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Configuration of the allow & block lists.
+#[derive(Debug, Clone, Default)]
+pub struct PeerListConfig {
+    /// File to load the lists from and save them to, if any; the lists are kept in memory otherwise.
+    pub path: Option<PathBuf>,
+    /// Only allow connections with the peers in the allow list, e.g. for closed groups.
+    ///
+    /// Otherwise, connections are allowed with all peers that are not blocked.
+    pub allow_only: bool,
+}
+
+/// Allowed & blocked peers; a peer is in at most one of them.
+#[derive(Debug, Default)]
+pub(crate) struct PeerLists {
+    path: Option<PathBuf>,
+    allow_only: bool,
+    pub(crate) allowed: HashSet<PeerId>,
+    pub(crate) blocked: HashSet<PeerId>,
+}
+
+impl PeerLists {
+    /// Loads the lists from the configured file, or starts empty if there is no such file.
+    pub(crate) fn load(config: &PeerListConfig) -> io::Result<Self> {
+        let mut lists = Self {
+            path: config.path.clone(),
+            allow_only: config.allow_only,
+            ..Default::default()
+        };
+
+        let content = match &config.path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(lists),
+                Err(err) => return Err(err),
+            },
+            None => return Ok(lists),
+        };
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid =
+                || io::Error::new(io::ErrorKind::InvalidData, format!("invalid line: {line}"));
+            let (list, peer_id) = line.split_once(' ').ok_or_else(invalid)?;
+            let peer_id = peer_id.trim().parse::<PeerId>().map_err(|_| invalid())?;
+            match list {
+                "allow" => lists.allow(peer_id),
+                "block" => lists.block(peer_id),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(lists)
+    }
+
+    /// Saves the lists to the configured file, if any.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let allowed = self
+            .allowed
+            .iter()
+            .map(|peer_id| format!("allow {peer_id}\n"));
+        let blocked = self
+            .blocked
+            .iter()
+            .map(|peer_id| format!("block {peer_id}\n"));
+        fs::write(path, allowed.chain(blocked).collect::<String>())
+    }
+
+    /// Returns whether connections with the peer are allowed.
+    pub(crate) fn is_permitted(&self, peer_id: &PeerId) -> bool {
+        if self.allow_only {
+            self.allowed.contains(peer_id)
+        } else {
+            !self.blocked.contains(peer_id)
+        }
+    }
+
+    pub(crate) fn allow(&mut self, peer_id: PeerId) {
+        self.blocked.remove(&peer_id);
+        self.allowed.insert(peer_id);
+    }
+
+    pub(crate) fn block(&mut self, peer_id: PeerId) {
+        self.allowed.remove(&peer_id);
+        self.blocked.insert(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("peers-{}.txt", std::process::id()));
+        let config = PeerListConfig {
+            path: Some(path.clone()),
+            allow_only: false,
+        };
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let mut lists = PeerLists::load(&config).unwrap();
+        assert!(lists.allowed.is_empty() && lists.blocked.is_empty());
+        lists.allow(alice);
+        lists.block(bob);
+        lists.allow(carol);
+        lists.block(carol);
+        lists.save().unwrap();
+
+        let loaded = PeerLists::load(&config).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.allowed, HashSet::from([alice]));
+        assert_eq!(loaded.blocked, HashSet::from([bob, carol]));
+        assert!(loaded.is_permitted(&alice));
+        assert!(!loaded.is_permitted(&bob));
+    }
+
+    #[test]
+    fn load_invalid() {
+        let path = std::env::temp_dir().join(format!("peers-invalid-{}.txt", std::process::id()));
+        fs::write(&path, "# comment\n\nallow not-a-peer\n").unwrap();
+        let config = PeerListConfig {
+            path: Some(path.clone()),
+            allow_only: false,
+        };
+
+        let err = PeerLists::load(&config).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn allow_only() {
+        let config = PeerListConfig {
+            path: None,
+            allow_only: true,
+        };
+        let mut lists = PeerLists::load(&config).unwrap();
+        let peer_id = PeerId::random();
+
+        assert!(!lists.is_permitted(&peer_id));
+        lists.allow(peer_id);
+        assert!(lists.is_permitted(&peer_id));
+        lists.save().unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! A table of the peers we have come across, and what we know about them.
+//!
+//! Disconnected peers are kept for [`DISCONNECTED_RETENTION`], so that the table doesn't grow
+//! with every peer that ever connected.
+use crate::ProtocolVersion;
+use libp2p::{Multiaddr, PeerId, StreamProtocol, gossipsub::TopicHash, identify};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// How long disconnected peers are kept in the table.
+pub(crate) const DISCONNECTED_RETENTION: Duration = Duration::from_secs(10 * 60);
+/// How often the disconnected peers are pruned, see [`PeerTable::prune`].
+pub(crate) const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything we know about a peer.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// Number of established connections, the peer is disconnected if zero.
+    pub connections: u32,
+    /// Protocol version advertised by the peer over identify.
+    pub protocol_version: Option<String>,
+    /// Protocol version negotiated with the peer, if it is compatible.
+    pub negotiated_version: Option<ProtocolVersion>,
+    /// Agent version advertised by the peer over identify.
+    pub agent_version: Option<String>,
+    /// Addresses the peer listens on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Protocols supported by the peer.
+    pub protocols: Vec<StreamProtocol>,
+    /// Our address, as observed by the peer.
+    pub observed_addr: Option<Multiaddr>,
+    /// Gossipsub topics the peer is subscribed to.
+    pub topics: HashSet<TopicHash>,
+    /// Whether the peer is in our gossipsub mesh for the chat topic.
+    ///
+    /// Peers join the mesh at a gossipsub heartbeat after subscribing, and leave it when
+    /// their score drops below zero.
+    pub in_mesh: bool,
+    /// Average round-trip time over the most recent pings, see [`PingConfig`](crate::PingConfig).
+    pub rtt: Option<Duration>,
+    /// Most recent round-trip times, the newest last.
+    rtt_samples: VecDeque<Duration>,
+    /// When we first heard of the peer.
+    pub first_seen: SystemTime,
+    /// When we last heard from the peer.
+    pub last_seen: SystemTime,
+}
+
+impl PeerInfo {
+    fn new() -> Self {
+        let now = SystemTime::now();
+        Self {
+            connections: 0,
+            protocol_version: None,
+            negotiated_version: None,
+            agent_version: None,
+            listen_addrs: Vec::new(),
+            protocols: Vec::new(),
+            observed_addr: None,
+            topics: Default::default(),
+            in_mesh: false,
+            rtt: None,
+            rtt_samples: VecDeque::new(),
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    /// Returns whether we have at least one connection with the peer.
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.connections > 0
+    }
+}
+
+/// Peers we have come across, including the recently disconnected ones.
+#[derive(Debug, Default)]
+pub(crate) struct PeerTable {
+    peers: HashMap<PeerId, PeerInfo>,
+}
+
+impl PeerTable {
+    /// Returns the peer's entry, creating it if needed, and marks it as seen now.
+    pub(crate) fn seen(&mut self, peer_id: PeerId) -> &mut PeerInfo {
+        let info = self.peers.entry(peer_id).or_insert_with(PeerInfo::new);
+        info.last_seen = SystemTime::now();
+        info
+    }
+
+    /// Records what the peer has told us over identify.
+    pub(crate) fn identified(&mut self, peer_id: PeerId, info: &identify::Info) {
+        let peer = self.seen(peer_id);
+        peer.protocol_version = Some(info.protocol_version.clone());
+        peer.agent_version = Some(info.agent_version.clone());
+        peer.listen_addrs = info.listen_addrs.clone();
+        peer.protocols = info.protocols.clone();
+        peer.observed_addr = Some(info.observed_addr.clone());
+    }
+
+    /// Records a successful ping, keeping the average of the last `window` round-trip times.
+    pub(crate) fn pinged(&mut self, peer_id: PeerId, rtt: Duration, window: usize) {
+        let peer = self.seen(peer_id);
+        peer.rtt_samples.push_back(rtt);
+        while peer.rtt_samples.len() > window.max(1) {
+            peer.rtt_samples.pop_front();
+        }
+        let total = peer.rtt_samples.iter().sum::<Duration>();
+        peer.rtt = Some(total / peer.rtt_samples.len() as u32);
+    }
+
+    /// Forgets the peers that have been disconnected for longer than `retention`.
+    pub(crate) fn prune(&mut self, retention: Duration) {
+        self.peers.retain(|_, info| {
+            info.is_connected() || info.last_seen.elapsed().is_ok_and(|idle| idle < retention)
+        });
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, peer_id: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer_id)
+    }
+
+    #[inline]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&PeerId, &PeerInfo)> {
+        self.peers.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_disconnected() {
+        let mut table = PeerTable::default();
+        let (connected, recent, gone) = (PeerId::random(), PeerId::random(), PeerId::random());
+        table.seen(connected).connections = 1;
+        table.seen(connected).last_seen -= Duration::from_secs(60);
+        table.seen(recent);
+        table.seen(gone).last_seen -= Duration::from_secs(60);
+
+        table.prune(Duration::from_secs(30));
+        assert!(table.get(&connected).is_some());
+        assert!(table.get(&recent).is_some());
+        assert!(table.get(&gone).is_none());
+    }
+}
@@ -0,0 +1,228 @@
+//! Per-author rate limiting of received messages.
+//!
+//! Each author has a token bucket that refills at a constant rate; a gossipsub message takes one
+//! token, and messages without a token are rejected. Chunks of a payload are gossipsub messages
+//! of their own, so they are checked before the payload is reassembled. Authors that keep going over the limit are
+//! disconnected, and their connections are refused for a while.
+//!
+//! Buckets that have refilled are the same as new ones, so they are pruned every now and then.
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Bounds of [`RateLimiter::prune_interval`].
+const MIN_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Configuration of the per-author rate limiter.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Tokens added per second, i.e. the sustained number of messages per second of an author.
+    pub rate: f64,
+    /// Capacity of the bucket, i.e. how many messages an author can send in a burst.
+    ///
+    /// Should fit the number of chunks of the largest payload, see
+    /// [`GossipsubConfig::max_message_size`](crate::GossipsubConfig::max_message_size).
+    pub burst: f64,
+    /// Number of rejected messages after which the author is disconnected.
+    pub max_violations: u32,
+    /// How long the connections of a disconnected author are refused.
+    pub ban_duration: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate: 5.0,
+            // 4 MiB payloads take 65 chunks of 64 KiB gossipsub messages
+            burst: 100.0,
+            max_violations: 50,
+            ban_duration: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Outcome of [`RateLimiter::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RateLimit {
+    /// Message is within the limit.
+    Allowed,
+    /// Message is over the limit.
+    Limited,
+    /// Message is over the limit, and the author has just been banned.
+    Banned,
+}
+
+struct Bucket {
+    tokens: f64,
+    violations: u32,
+    updated: Instant,
+}
+
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<PeerId, Bucket>,
+    /// Banned peers, and when their ban ends.
+    banned: HashMap<PeerId, Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+            banned: Default::default(),
+        }
+    }
+
+    /// Takes a token from the author's bucket.
+    pub(crate) fn check(&mut self, author: PeerId) -> RateLimit {
+        if self.is_banned(&author) {
+            return RateLimit::Limited;
+        }
+
+        let now = Instant::now();
+        let config = &self.config;
+        let bucket = self.buckets.entry(author).or_insert_with(|| Bucket {
+            tokens: config.burst,
+            violations: 0,
+            updated: now,
+        });
+
+        // refill, and forgive past violations once the bucket is full again
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * config.rate).min(config.burst);
+        bucket.updated = now;
+        if bucket.tokens >= config.burst {
+            bucket.violations = 0;
+        }
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return RateLimit::Allowed;
+        }
+
+        bucket.violations += 1;
+        if bucket.violations < config.max_violations {
+            return RateLimit::Limited;
+        }
+
+        self.buckets.remove(&author);
+        self.banned.insert(author, now + config.ban_duration);
+        RateLimit::Banned
+    }
+
+    /// Forgets the buckets that have refilled since they were last used, as they would be new.
+    pub(crate) fn prune(&mut self) {
+        let now = Instant::now();
+        let config = &self.config;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * config.rate < config.burst
+        });
+    }
+
+    /// How often to call [`Self::prune`]: about the time an empty bucket takes to refill.
+    pub(crate) fn prune_interval(&self) -> Duration {
+        Duration::try_from_secs_f64(self.config.burst / self.config.rate)
+            .unwrap_or(MAX_PRUNE_INTERVAL)
+            .clamp(MIN_PRUNE_INTERVAL, MAX_PRUNE_INTERVAL)
+    }
+
+    /// Returns whether the peer is banned, forgetting the bans that have ended.
+    pub(crate) fn is_banned(&mut self, peer_id: &PeerId) -> bool {
+        let now = Instant::now();
+        self.banned.retain(|_, until| *until > now);
+        self.banned.contains_key(peer_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: f64, max_violations: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            rate,
+            burst,
+            max_violations,
+            ban_duration: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn burst_then_limited() {
+        let mut limiter = limiter(0.001, 3.0, 10);
+        let author = PeerId::random();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(author), RateLimit::Allowed);
+        }
+        assert_eq!(limiter.check(author), RateLimit::Limited);
+
+        // buckets are per author
+        assert_eq!(limiter.check(PeerId::random()), RateLimit::Allowed);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = limiter(1000.0, 1.0, 10);
+        let author = PeerId::random();
+
+        assert_eq!(limiter.check(author), RateLimit::Allowed);
+        assert_eq!(limiter.check(author), RateLimit::Limited);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(limiter.check(author), RateLimit::Allowed);
+    }
+
+    #[test]
+    fn bans_repeat_offenders() {
+        let mut limiter = limiter(0.001, 1.0, 2);
+        let author = PeerId::random();
+
+        assert_eq!(limiter.check(author), RateLimit::Allowed);
+        assert_eq!(limiter.check(author), RateLimit::Limited);
+        assert_eq!(limiter.check(author), RateLimit::Banned);
+        assert!(limiter.is_banned(&author));
+        assert_eq!(limiter.check(author), RateLimit::Limited);
+        assert!(!limiter.is_banned(&PeerId::random()));
+    }
+
+    #[test]
+    fn bans_end() {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            rate: 0.001,
+            burst: 1.0,
+            max_violations: 1,
+            ban_duration: Duration::from_millis(5),
+        });
+        let author = PeerId::random();
+
+        assert_eq!(limiter.check(author), RateLimit::Allowed);
+        assert_eq!(limiter.check(author), RateLimit::Banned);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!limiter.is_banned(&author));
+        assert_eq!(limiter.check(author), RateLimit::Allowed);
+    }
+
+    #[test]
+    fn prunes_refilled_buckets() {
+        let mut limiter = limiter(1000.0, 2.0, 10);
+        let (idle, busy) = (PeerId::random(), PeerId::random());
+
+        assert_eq!(limiter.check(idle), RateLimit::Allowed);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(limiter.check(busy), RateLimit::Allowed);
+        assert_eq!(limiter.check(busy), RateLimit::Allowed);
+        limiter.prune();
+        assert!(!limiter.buckets.contains_key(&idle));
+        assert!(limiter.buckets.contains_key(&busy));
+
+        // a zero rate never refills
+        assert_eq!(limiter.prune_interval(), MIN_PRUNE_INTERVAL);
+        assert_eq!(
+            self::limiter(0.0, 2.0, 10).prune_interval(),
+            MAX_PRUNE_INTERVAL
+        );
+    }
+}
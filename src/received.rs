@@ -0,0 +1,343 @@
+//! The bounded queue of received messages, waiting to be read by the application.
+//!
+//! Applications that poll the queue (like the FFI example, every 250ms) may fall behind a busy
+//! topic, so the queue holds at most a given number of messages and drops the rest.
+//!
+//! Messages can be forwarded to a bounded channel instead, for applications that would rather be
+//! notified (like FFI callbacks); those that don't fit in the channel are dropped the same way.
+//!
+//! On Unix, the queue can also provide a file descriptor that is readable while it is not empty,
+//! so that event loops (e.g. with `epoll`) know when to read it.
+use crate::ChatMessage;
+use prometheus_client::metrics::counter::Counter;
+use std::collections::VecDeque;
+use std::collections::vec_deque;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::{io::AsRawFd, io::RawFd, net::UnixStream};
+
+/// Which message to drop when the received queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceivedOverflow {
+    /// Drop the oldest message in the queue to make room for the new one.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+}
+
+/// Configuration of the received queue.
+#[derive(Debug, Clone)]
+pub struct ReceivedConfig {
+    /// Maximum number of messages waiting to be read.
+    pub capacity: usize,
+    /// What to do with a received message when the queue is full.
+    pub overflow: ReceivedOverflow,
+}
+
+impl Default for ReceivedConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            overflow: ReceivedOverflow::DropOldest,
+        }
+    }
+}
+
+/// Messages received and accepted, oldest first.
+#[derive(Debug)]
+pub struct ReceivedQueue {
+    messages: VecDeque<ChatMessage>,
+    capacity: usize,
+    overflow: ReceivedOverflow,
+    dropped: Counter,
+    /// Channel that the messages are forwarded to instead, if any.
+    forward: Option<mpsc::Sender<ChatMessage>>,
+    /// Readable while the queue is not empty, once requested.
+    #[cfg(unix)]
+    readiness: Option<Readiness>,
+}
+
+impl ReceivedQueue {
+    pub(crate) fn new(config: &ReceivedConfig, dropped: Counter) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            capacity: config.capacity.max(1),
+            overflow: config.overflow,
+            dropped,
+            forward: None,
+            #[cfg(unix)]
+            readiness: None,
+        }
+    }
+
+    /// Sets the channel to forward the messages to instead of queueing them, or queues them again with `None`.
+    ///
+    /// Messages already in the queue stay there.
+    pub(crate) fn set_forward(&mut self, forward: Option<mpsc::Sender<ChatMessage>>) {
+        self.forward = forward;
+    }
+
+    /// Adds a message to the queue, and returns the message that is dropped as per the overflow policy.
+    ///
+    /// If the messages are forwarded, the new message is dropped when the channel is full;
+    /// once the channel is closed, messages are queued again.
+    pub(crate) fn push(&mut self, mut message: ChatMessage) -> Option<ChatMessage> {
+        if let Some(forward) = &self.forward {
+            match forward.try_send(message) {
+                Ok(()) => return None,
+                Err(TrySendError::Full(message)) => {
+                    self.dropped.inc();
+                    return Some(message);
+                }
+                Err(TrySendError::Closed(closed)) => {
+                    self.forward = None;
+                    message = closed;
+                }
+            }
+        }
+
+        if self.messages.len() < self.capacity {
+            self.messages.push_back(message);
+            self.update_readiness();
+            return None;
+        }
+
+        self.dropped.inc();
+        match self.overflow {
+            ReceivedOverflow::DropOldest => {
+                let oldest = self.messages.pop_front();
+                self.messages.push_back(message);
+                oldest
+            }
+            ReceivedOverflow::DropNewest => Some(message),
+        }
+    }
+
+    /// Removes & returns the oldest message.
+    #[inline]
+    pub fn pop_front(&mut self) -> Option<ChatMessage> {
+        let message = self.messages.pop_front();
+        self.update_readiness();
+        message
+    }
+
+    /// Removes & returns the oldest message if its data is at most `max_len` bytes.
+    ///
+    /// Otherwise, the message is left in the queue and the length of its data is returned,
+    /// so that it can be received with a larger buffer.
+    pub fn pop_front_within(&mut self, max_len: usize) -> Result<Option<ChatMessage>, usize> {
+        match self.messages.front() {
+            Some(message) if message.data.len() > max_len => Err(message.data.len()),
+            Some(_) => Ok(self.pop_front()),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the oldest message, without removing it.
+    #[inline]
+    pub fn front(&self) -> Option<&ChatMessage> {
+        self.messages.front()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Maximum number of messages held by the queue.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of messages dropped because the queue was full.
+    #[inline]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get()
+    }
+
+    /// Iterates over the messages, oldest first.
+    pub fn iter(&self) -> vec_deque::Iter<'_, ChatMessage> {
+        self.messages.iter()
+    }
+
+    /// Returns a file descriptor that is readable while the queue is not empty, e.g. to be polled with `epoll`.
+    ///
+    /// The descriptor is owned by the queue, so it must not be read from or closed, and is only valid
+    /// as long as the queue is. Messages forwarded to a channel don't make it readable.
+    #[cfg(unix)]
+    pub fn readiness_fd(&mut self) -> io::Result<RawFd> {
+        if self.readiness.is_none() {
+            self.readiness = Some(Readiness::new()?);
+            self.update_readiness();
+        }
+        Ok(self
+            .readiness
+            .as_ref()
+            .expect("should be created")
+            .reader
+            .as_raw_fd())
+    }
+
+    /// Makes the readiness descriptor readable or not, as per the messages.
+    #[inline]
+    fn update_readiness(&mut self) {
+        #[cfg(unix)]
+        if let Some(readiness) = &mut self.readiness {
+            readiness.set(!self.messages.is_empty());
+        }
+    }
+}
+
+/// A socket pair, whose reader is readable while the writer has written to it.
+#[cfg(unix)]
+#[derive(Debug)]
+struct Readiness {
+    reader: UnixStream,
+    writer: UnixStream,
+    ready: bool,
+}
+
+#[cfg(unix)]
+impl Readiness {
+    fn new() -> io::Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        // so that a host reading from it can't block the client
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+        Ok(Self {
+            reader,
+            writer,
+            ready: false,
+        })
+    }
+
+    fn set(&mut self, ready: bool) {
+        if ready == self.ready {
+            return;
+        }
+
+        if ready {
+            if let Err(err) = self.writer.write(&[1]) {
+                log::warn!("Could not signal received messages: {err}");
+            }
+        } else {
+            let mut buf = [0u8; 16];
+            while matches!(self.reader.read(&mut buf), Ok(n) if n > 0) {}
+        }
+        self.ready = ready;
+    }
+}
+
+impl<'a> IntoIterator for &'a ReceivedQueue {
+    type Item = &'a ChatMessage;
+    type IntoIter = vec_deque::Iter<'a, ChatMessage>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.messages.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::{PeerId, gossipsub::TopicHash};
+    use std::time::SystemTime;
+
+    fn message(data: &str) -> ChatMessage {
+        let topic = TopicHash::from_raw("test");
+        ChatMessage::new(PeerId::random(), topic, data.into(), 0, SystemTime::now())
+    }
+
+    fn queue(capacity: usize, overflow: ReceivedOverflow) -> ReceivedQueue {
+        let config = ReceivedConfig { capacity, overflow };
+        ReceivedQueue::new(&config, Counter::default())
+    }
+
+    fn data(queue: &ReceivedQueue) -> Vec<&str> {
+        queue.iter().map(|message| message.data.as_str()).collect()
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut queue = queue(2, ReceivedOverflow::DropOldest);
+        assert!(queue.push(message("a")).is_none());
+        assert!(queue.push(message("b")).is_none());
+        assert_eq!(queue.push(message("c")).unwrap().data, "a");
+        assert_eq!(data(&queue), ["b", "c"]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut queue = queue(2, ReceivedOverflow::DropNewest);
+        assert!(queue.push(message("a")).is_none());
+        assert!(queue.push(message("b")).is_none());
+        assert_eq!(queue.push(message("c")).unwrap().data, "c");
+        assert_eq!(data(&queue), ["a", "b"]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn forward() {
+        let mut queue = queue(2, ReceivedOverflow::DropOldest);
+        let (forward, mut messages) = mpsc::channel(1);
+        queue.set_forward(Some(forward));
+        assert!(queue.push(message("a")).is_none());
+        assert_eq!(queue.push(message("b")).unwrap().data, "b");
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(messages.try_recv().unwrap().data, "a");
+        assert!(queue.is_empty());
+
+        // queued again once the channel is closed
+        drop(messages);
+        assert!(queue.push(message("c")).is_none());
+        assert_eq!(data(&queue), ["c"]);
+    }
+
+    /// Whether the descriptor is readable, without reading from it.
+    #[cfg(unix)]
+    async fn readable(fd: RawFd) -> bool {
+        use tokio::io::{Interest, unix::AsyncFd};
+        let fd = AsyncFd::with_interest(fd, Interest::READABLE).unwrap();
+        let ready = fd.readable();
+        tokio::time::timeout(std::time::Duration::from_millis(20), ready)
+            .await
+            .is_ok()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn readiness_fd() {
+        let mut queue = queue(2, ReceivedOverflow::DropOldest);
+        let fd = queue.readiness_fd().unwrap();
+        assert_eq!(queue.readiness_fd().unwrap(), fd);
+        assert!(!readable(fd).await);
+
+        queue.push(message("a"));
+        queue.push(message("b"));
+        assert!(readable(fd).await);
+        queue.pop_front();
+        assert!(readable(fd).await);
+        queue.pop_front();
+        assert!(!readable(fd).await);
+
+        // a message waiting before the descriptor is created
+        let mut queue = self::queue(2, ReceivedOverflow::DropOldest);
+        queue.push(message("a"));
+        let fd = queue.readiness_fd().unwrap();
+        assert!(readable(fd).await);
+        assert!(queue.pop_front_within(0).is_err());
+        assert!(readable(fd).await);
+        assert!(queue.pop_front_within(1).unwrap().is_some());
+        assert!(!readable(fd).await);
+    }
+}
@@ -0,0 +1,61 @@
+//! Graceful shutdown of the client.
+//!
+//! Once cancelled, the client stops accepting messages, publishes those already queued, leaves
+//! the chat topic and closes its connections, all within a drain timeout.
+use std::fmt;
+use std::time::Duration;
+
+/// Configuration of the shutdown.
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    /// How long to wait for the queued messages to go out, and the connections to close.
+    ///
+    /// Messages still in the outbox afterwards are kept in its file, if configured.
+    pub drain_timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What happened during the shutdown, returned by [`ChatClient::run`](crate::ChatClient::run).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Messages that were still queued in the channel, and were published (or kept in the outbox).
+    pub drained: usize,
+    /// Messages from the channel that could not be published.
+    pub failed: usize,
+    /// Messages left in the outbox, as there were no peers to publish them to.
+    pub unsent: usize,
+    /// Connections that were closed.
+    pub connections_closed: usize,
+    /// Connections that were still open once the drain timeout passed.
+    pub connections_open: usize,
+    /// Whether the drain timeout passed before the shutdown was complete.
+    pub timed_out: bool,
+    /// How long the shutdown took.
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drained {} messages ({} failed, {} unsent), closed {} connections ({} left open) in {:?}",
+            self.drained,
+            self.failed,
+            self.unsent,
+            self.connections_closed,
+            self.connections_open,
+            self.elapsed
+        )?;
+        if self.timed_out {
+            write!(f, ", timed out")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,832 @@
+//! File transfer between two peers, built on top of [`request_response`].
+//!
+//! The protocol is pull-based: the sender offers a file, and the receiver asks for it chunk by chunk.
+//!
+//! 1. Sender sends [`FileRequest::Offer`] with the file name, size and SHA-256 hash.
+//! 2. Receiver responds with [`FileResponse::Accepted`], telling how many bytes it already has.
+//! 3. Receiver sends [`FileRequest::Chunk`] for each offset, sender responds with [`FileResponse::Chunk`].
+//! 4. Receiver verifies the hash and sends [`FileRequest::Done`], sender responds with [`FileResponse::Ack`].
+//!
+//! Partial files are stored as `{download_dir}/{sha256}-{peer_id}.part`, so the same peer offering
+//! the same file again after an interruption resumes from where it was left. Only one transfer
+//! writes to a partial file at a time: a second offer of it is rejected while the first is ongoing.
+//!
+//! Offered files are kept open until the receiver is done, or stops asking for chunks for
+//! [`TransferConfig::idle_timeout`].
+//!
+//! Offers are only accepted from peers in the allow list by default, and up to
+//! [`TransferConfig::max_file_size`]; completed files never overwrite existing ones.
+use libp2p::{PeerId, StreamProtocol, request_response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Identifier of a transfer, unique per peer & direction.
+pub type TransferId = u64;
+
+/// The underlying request-response behaviour for file transfers.
+pub type TransferBehaviour = request_response::cbor::Behaviour<FileRequest, FileResponse>;
+
+/// Protocol name for file transfers.
+pub(crate) const TRANSFER_PROTOCOL: StreamProtocol = StreamProtocol::new("/chat/file/1.0.0");
+
+/// Configuration for file transfers.
+#[derive(Debug, Clone)]
+pub struct TransferConfig {
+    /// Directory where received files are stored.
+    pub download_dir: PathBuf,
+    /// Number of bytes sent per chunk.
+    pub chunk_size: u64,
+    /// Largest file accepted from a peer, in bytes.
+    pub max_file_size: u64,
+    /// Only accept files from peers in the allow list, see [`PeerListConfig`](crate::PeerListConfig).
+    ///
+    /// Otherwise, files are accepted from all peers that are not blocked.
+    pub allow_listed_only: bool,
+    /// Maximum number of events kept until they are consumed; the oldest are dropped first.
+    ///
+    /// Progress events are coalesced per transfer, so this mostly bounds finished transfers.
+    pub max_events: usize,
+    /// Offered files that the peer hasn't asked a chunk of for this long are closed, and their
+    /// transfer fails.
+    pub idle_timeout: Duration,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            download_dir: PathBuf::from("downloads"),
+            chunk_size: 64 * 1024,
+            max_file_size: 100 * 1024 * 1024,
+            allow_listed_only: true,
+            max_events: 256,
+            idle_timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Requests of the file transfer protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileRequest {
+    /// Sender offers a file to the receiver.
+    Offer {
+        id: TransferId,
+        name: String,
+        size: u64,
+        sha256: [u8; 32],
+    },
+    /// Receiver asks for the chunk starting at `offset`.
+    Chunk { id: TransferId, offset: u64 },
+    /// Receiver has received all chunks, and tells whether the hash matched.
+    Done { id: TransferId, verified: bool },
+}
+
+/// Responses of the file transfer protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileResponse {
+    /// Offer is accepted, receiver already has `offset` bytes.
+    Accepted { offset: u64 },
+    /// A chunk of the file.
+    Chunk {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// Acknowledges [`FileRequest::Done`].
+    Ack,
+    /// Request could not be served.
+    Rejected { reason: String },
+}
+
+/// Direction of a transfer, from the point of view of this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Outgoing,
+    Incoming,
+}
+
+/// Events emitted during file transfers.
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    /// Transfer has started, resuming from `offset` bytes if non-zero.
+    Started {
+        peer_id: PeerId,
+        id: TransferId,
+        direction: TransferDirection,
+        name: String,
+        size: u64,
+        offset: u64,
+    },
+    /// Transfer has progressed up to `bytes` out of `size`.
+    Progress {
+        peer_id: PeerId,
+        id: TransferId,
+        direction: TransferDirection,
+        bytes: u64,
+        size: u64,
+    },
+    /// Transfer has finished and the hash is verified.
+    Completed {
+        peer_id: PeerId,
+        id: TransferId,
+        direction: TransferDirection,
+        path: PathBuf,
+    },
+    /// Transfer has failed; incoming partial files are kept for resuming.
+    Failed {
+        peer_id: PeerId,
+        id: TransferId,
+        direction: TransferDirection,
+        reason: String,
+    },
+}
+
+struct Outgoing {
+    path: PathBuf,
+    file: File,
+    name: String,
+    size: u64,
+    /// When the offer was made, or the last chunk was asked for.
+    active: Instant,
+}
+
+struct Incoming {
+    name: String,
+    size: u64,
+    sha256: [u8; 32],
+    offset: u64,
+    file: File,
+    part_path: PathBuf,
+    hasher: Sha256,
+}
+
+/// State of all ongoing transfers.
+pub(crate) struct Transfers {
+    config: TransferConfig,
+    next_id: TransferId,
+    outgoing: HashMap<(PeerId, TransferId), Outgoing>,
+    incoming: HashMap<(PeerId, TransferId), Incoming>,
+    /// Maps our pending requests to their transfers.
+    requests: HashMap<request_response::OutboundRequestId, (PeerId, TransferId, TransferDirection)>,
+    /// Events to be consumed by the user, see [`Transfers::push_event`].
+    events: VecDeque<TransferEvent>,
+}
+
+impl Transfers {
+    pub(crate) fn new(config: TransferConfig) -> Self {
+        Self {
+            config,
+            next_id: 0,
+            outgoing: Default::default(),
+            incoming: Default::default(),
+            requests: Default::default(),
+            events: Default::default(),
+        }
+    }
+
+    /// Offers the file at `path` to the given peer.
+    ///
+    /// The file is hashed here, so this is meant for logs & small binaries.
+    pub(crate) fn offer(
+        &mut self,
+        behaviour: &mut TransferBehaviour,
+        peer_id: PeerId,
+        path: &Path,
+    ) -> io::Result<TransferId> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)?;
+        let sha256 = hasher.finalize().into();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        log::info!("Offering {name} ({size} bytes) to {peer_id}");
+        let request = FileRequest::Offer {
+            id,
+            name: name.clone(),
+            size,
+            sha256,
+        };
+        let request_id = behaviour.send_request(&peer_id, request);
+        self.requests
+            .insert(request_id, (peer_id, id, TransferDirection::Outgoing));
+        self.outgoing.insert(
+            (peer_id, id),
+            Outgoing {
+                path: path.to_path_buf(),
+                file,
+                name,
+                size,
+                active: Instant::now(),
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Drops the outgoing transfers that the peer has stopped pulling, closing their files.
+    pub(crate) fn prune(&mut self) {
+        let timeout = self.config.idle_timeout;
+        let idle = self
+            .outgoing
+            .iter()
+            .filter(|(_, outgoing)| outgoing.active.elapsed() >= timeout)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for (peer_id, id) in idle {
+            let reason = format!("no chunk asked for in {timeout:?}");
+            self.fail(peer_id, id, TransferDirection::Outgoing, reason);
+        }
+    }
+
+    /// How often to call [`Self::prune`].
+    pub(crate) fn prune_interval(&self) -> Duration {
+        (self.config.idle_timeout / 2).max(Duration::from_millis(10))
+    }
+
+    /// Pops the oldest event.
+    pub(crate) fn next_event(&mut self) -> Option<TransferEvent> {
+        self.events.pop_front()
+    }
+
+    /// Queues an event, replacing the pending progress of the same transfer if any.
+    ///
+    /// A chunk-sized transfer would otherwise queue one event per chunk while nobody reads them.
+    fn push_event(&mut self, event: TransferEvent) {
+        if let TransferEvent::Progress {
+            peer_id,
+            id,
+            direction,
+            ..
+        } = event
+        {
+            let pending = self.events.iter_mut().find(|pending| {
+                matches!(pending, TransferEvent::Progress { peer_id: p, id: i, direction: d, .. }
+                    if *p == peer_id && *i == id && *d == direction)
+            });
+            if let Some(pending) = pending {
+                *pending = event;
+                return;
+            }
+        }
+
+        if self.events.len() >= self.config.max_events {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Handles an incoming request, and responds to it.
+    ///
+    /// Offers are rejected unless `allowed` tells that the peer is in the allow list, when
+    /// [`TransferConfig::allow_listed_only`] is set.
+    pub(crate) fn on_request(
+        &mut self,
+        behaviour: &mut TransferBehaviour,
+        peer_id: PeerId,
+        allowed: bool,
+        request: FileRequest,
+        channel: request_response::ResponseChannel<FileResponse>,
+    ) {
+        let response = self.respond(behaviour, peer_id, allowed, request);
+        if behaviour.send_response(channel, response).is_err() {
+            log::warn!("Could not respond to file request from {peer_id}");
+        }
+    }
+
+    /// Handles an incoming request, and returns the response, see [`Self::on_request`].
+    fn respond(
+        &mut self,
+        behaviour: &mut TransferBehaviour,
+        peer_id: PeerId,
+        allowed: bool,
+        request: FileRequest,
+    ) -> FileResponse {
+        match request {
+            FileRequest::Offer { name, .. } if self.config.allow_listed_only && !allowed => {
+                log::warn!("Rejected {name} from {peer_id}, which is not in the allow list");
+                FileResponse::Rejected {
+                    reason: "sender is not allowed".into(),
+                }
+            }
+            FileRequest::Offer { name, size, .. } if size > self.config.max_file_size => {
+                log::warn!("Rejected {name} from {peer_id}, {size} bytes is too large");
+                FileResponse::Rejected {
+                    reason: format!("file is larger than {} bytes", self.config.max_file_size),
+                }
+            }
+            FileRequest::Offer {
+                id,
+                name,
+                size,
+                sha256,
+            } => match self.accept(peer_id, id, name, size, sha256) {
+                Ok(offset) => {
+                    self.request_next(behaviour, peer_id, id);
+                    FileResponse::Accepted { offset }
+                }
+                Err(err) => {
+                    log::error!("Could not accept file from {peer_id}: {err}");
+                    FileResponse::Rejected {
+                        reason: err.to_string(),
+                    }
+                }
+            },
+            FileRequest::Chunk { id, offset } => match self.read_chunk(peer_id, id, offset) {
+                Ok(data) => FileResponse::Chunk { data },
+                Err(err) => {
+                    log::error!("Could not read chunk for {peer_id}: {err}");
+                    FileResponse::Rejected {
+                        reason: err.to_string(),
+                    }
+                }
+            },
+            FileRequest::Done { id, verified } => {
+                if let Some(outgoing) = self.outgoing.remove(&(peer_id, id)) {
+                    let direction = TransferDirection::Outgoing;
+                    self.push_event(if verified {
+                        log::info!("Sent {} to {peer_id}", outgoing.name);
+                        TransferEvent::Completed {
+                            peer_id,
+                            id,
+                            direction,
+                            path: outgoing.path,
+                        }
+                    } else {
+                        TransferEvent::Failed {
+                            peer_id,
+                            id,
+                            direction,
+                            reason: "hash mismatch at receiver".into(),
+                        }
+                    });
+                }
+                FileResponse::Ack
+            }
+        }
+    }
+
+    /// Handles a response to one of our requests.
+    pub(crate) fn on_response(
+        &mut self,
+        behaviour: &mut TransferBehaviour,
+        request_id: request_response::OutboundRequestId,
+        response: FileResponse,
+    ) {
+        let Some((peer_id, id, direction)) = self.requests.remove(&request_id) else {
+            return;
+        };
+
+        match response {
+            FileResponse::Accepted { offset } => {
+                if let Some(outgoing) = self.outgoing.get(&(peer_id, id)) {
+                    let event = TransferEvent::Started {
+                        peer_id,
+                        id,
+                        direction,
+                        name: outgoing.name.clone(),
+                        size: outgoing.size,
+                        offset,
+                    };
+                    self.push_event(event);
+                }
+            }
+            FileResponse::Chunk { data } => {
+                if let Err(err) = self.write_chunk(peer_id, id, &data) {
+                    self.fail(peer_id, id, direction, err.to_string());
+                } else {
+                    self.request_next(behaviour, peer_id, id);
+                }
+            }
+            FileResponse::Rejected { reason } => self.fail(peer_id, id, direction, reason),
+            FileResponse::Ack => { /* transfer is already finished on our side */ }
+        }
+    }
+
+    /// Handles a failed request, which interrupts its transfer.
+    pub(crate) fn on_failure(
+        &mut self,
+        request_id: request_response::OutboundRequestId,
+        reason: String,
+    ) {
+        if let Some((peer_id, id, direction)) = self.requests.remove(&request_id) {
+            self.fail(peer_id, id, direction, reason);
+        }
+    }
+
+    /// Interrupts all transfers with a disconnected peer.
+    pub(crate) fn on_disconnected(&mut self, peer_id: PeerId) {
+        let outgoing = self.outgoing.keys().filter(|(peer, _)| *peer == peer_id);
+        let outgoing = outgoing.map(|(_, id)| (*id, TransferDirection::Outgoing));
+        let incoming = self.incoming.keys().filter(|(peer, _)| *peer == peer_id);
+        let incoming = incoming.map(|(_, id)| (*id, TransferDirection::Incoming));
+
+        let interrupted = outgoing.chain(incoming).collect::<Vec<_>>();
+        for (id, direction) in interrupted {
+            self.fail(peer_id, id, direction, "connection closed".into());
+        }
+    }
+
+    /// Prepares the partial file for an offer, and returns the number of bytes we already have.
+    fn accept(
+        &mut self,
+        peer_id: PeerId,
+        id: TransferId,
+        name: String,
+        size: u64,
+        sha256: [u8; 32],
+    ) -> io::Result<u64> {
+        // only keep the file name, so that the sender can't write outside of the download dir
+        let name = Path::new(&name)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| hex(&sha256));
+
+        fs::create_dir_all(&self.config.download_dir)?;
+        let part_path = self
+            .config
+            .download_dir
+            .join(format!("{}-{peer_id}.part", hex(&sha256)));
+        if self
+            .incoming
+            .values()
+            .any(|incoming| incoming.part_path == part_path)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file is already being received",
+            ));
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part_path)?;
+
+        // hash what we already have, so that we can resume from there
+        let mut hasher = Sha256::new();
+        let mut offset = file.metadata()?.len();
+        if offset > size {
+            file.set_len(0)?;
+            offset = 0;
+        } else {
+            io::copy(&mut file, &mut hasher)?;
+        }
+
+        log::info!("Receiving {name} ({size} bytes) from {peer_id}, starting at {offset}");
+        self.push_event(TransferEvent::Started {
+            peer_id,
+            id,
+            direction: TransferDirection::Incoming,
+            name: name.clone(),
+            size,
+            offset,
+        });
+        self.incoming.insert(
+            (peer_id, id),
+            Incoming {
+                name,
+                size,
+                sha256,
+                offset,
+                file,
+                part_path,
+                hasher,
+            },
+        );
+
+        Ok(offset)
+    }
+
+    fn read_chunk(&mut self, peer_id: PeerId, id: TransferId, offset: u64) -> io::Result<Vec<u8>> {
+        let outgoing = self
+            .outgoing
+            .get_mut(&(peer_id, id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown transfer"))?;
+        outgoing.active = Instant::now();
+
+        let mut data = Vec::new();
+        outgoing.file.seek(SeekFrom::Start(offset))?;
+        (&mut outgoing.file)
+            .take(self.config.chunk_size)
+            .read_to_end(&mut data)?;
+
+        let (bytes, size) = (offset + data.len() as u64, outgoing.size);
+        log::debug!(
+            "Sent {bytes}/{size} bytes of {} to {peer_id}",
+            outgoing.name
+        );
+        self.push_event(TransferEvent::Progress {
+            peer_id,
+            id,
+            direction: TransferDirection::Outgoing,
+            bytes,
+            size,
+        });
+
+        Ok(data)
+    }
+
+    fn write_chunk(&mut self, peer_id: PeerId, id: TransferId, data: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+
+        let incoming = self
+            .incoming
+            .get_mut(&(peer_id, id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown transfer"))?;
+        if data.is_empty() || incoming.offset + data.len() as u64 > incoming.size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid chunk"));
+        }
+
+        incoming.file.seek(SeekFrom::Start(incoming.offset))?;
+        incoming.file.write_all(data)?;
+        incoming.hasher.update(data);
+        incoming.offset += data.len() as u64;
+
+        let (bytes, size) = (incoming.offset, incoming.size);
+        log::debug!(
+            "Received {bytes}/{size} bytes of {} from {peer_id}",
+            incoming.name
+        );
+        self.push_event(TransferEvent::Progress {
+            peer_id,
+            id,
+            direction: TransferDirection::Incoming,
+            bytes,
+            size,
+        });
+
+        Ok(())
+    }
+
+    /// Asks for the next chunk, or finishes the transfer if there are none left.
+    fn request_next(&mut self, behaviour: &mut TransferBehaviour, peer_id: PeerId, id: TransferId) {
+        let Some(incoming) = self.incoming.get(&(peer_id, id)) else {
+            return;
+        };
+
+        let direction = TransferDirection::Incoming;
+        if incoming.offset < incoming.size {
+            let request = FileRequest::Chunk {
+                id,
+                offset: incoming.offset,
+            };
+            let request_id = behaviour.send_request(&peer_id, request);
+            self.requests.insert(request_id, (peer_id, id, direction));
+            return;
+        }
+
+        let Some(incoming) = self.incoming.remove(&(peer_id, id)) else {
+            return;
+        };
+        // the response to `Done` is not tracked, the transfer is over for us either way
+        let verified = incoming.hasher.finalize().as_slice() == incoming.sha256;
+        behaviour.send_request(&peer_id, FileRequest::Done { id, verified });
+
+        let result = if verified {
+            self.complete(&incoming.part_path, &incoming.name)
+                .map_err(|err| err.to_string())
+        } else {
+            // the partial file is corrupt, so we can't resume from it
+            let _ = fs::remove_file(&incoming.part_path);
+            Err("hash mismatch".to_string())
+        };
+
+        self.push_event(match result {
+            Ok(path) => {
+                log::info!("Received {} from {peer_id}", path.display());
+                TransferEvent::Completed {
+                    peer_id,
+                    id,
+                    direction,
+                    path,
+                }
+            }
+            Err(reason) => {
+                log::warn!("Transfer {id} with {peer_id} failed: {reason}");
+                TransferEvent::Failed {
+                    peer_id,
+                    id,
+                    direction,
+                    reason,
+                }
+            }
+        });
+    }
+
+    /// Moves a verified partial file to its final name, and returns its path.
+    ///
+    /// If a file with that name already exists, ` (1)`, ` (2)`, ... is appended to the stem.
+    /// Hard linking fails rather than replacing an existing file, unlike renaming.
+    fn complete(&self, part_path: &Path, name: &str) -> io::Result<PathBuf> {
+        let name = Path::new(name);
+        let stem = name
+            .file_stem()
+            .unwrap_or(name.as_os_str())
+            .to_string_lossy();
+        let extension = name.extension().map(|ext| ext.to_string_lossy());
+
+        for n in 0..1000 {
+            let mut candidate = if n == 0 {
+                stem.to_string()
+            } else {
+                format!("{stem} ({n})")
+            };
+            if let Some(extension) = &extension {
+                candidate = format!("{candidate}.{extension}");
+            }
+
+            let path = self.config.download_dir.join(candidate);
+            match fs::hard_link(part_path, &path) {
+                Ok(()) => {
+                    fs::remove_file(part_path)?;
+                    return Ok(path);
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("too many files named {}", name.display()),
+        ))
+    }
+
+    /// Drops the transfer & emits a failure event.
+    fn fail(
+        &mut self,
+        peer_id: PeerId,
+        id: TransferId,
+        direction: TransferDirection,
+        reason: String,
+    ) {
+        let existed = match direction {
+            TransferDirection::Outgoing => self.outgoing.remove(&(peer_id, id)).is_some(),
+            TransferDirection::Incoming => self.incoming.remove(&(peer_id, id)).is_some(),
+        };
+
+        if existed {
+            log::warn!("Transfer {id} with {peer_id} failed: {reason}");
+            self.push_event(TransferEvent::Failed {
+                peer_id,
+                id,
+                direction,
+                reason,
+            });
+        }
+    }
+}
+
+/// Lowercase hex encoding of the given bytes.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfers(name: &str) -> Transfers {
+        let dir = std::env::temp_dir().join(format!("transfer-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Transfers::new(TransferConfig {
+            download_dir: dir,
+            chunk_size: 100,
+            max_file_size: 1000,
+            ..Default::default()
+        })
+    }
+
+    fn behaviour() -> TransferBehaviour {
+        use request_response::{Config, ProtocolSupport};
+        TransferBehaviour::new(
+            [(TRANSFER_PROTOCOL, ProtocolSupport::Full)],
+            Config::default(),
+        )
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    /// Pops the events, and returns the last one.
+    fn last_event(transfers: &mut Transfers) -> Option<TransferEvent> {
+        std::iter::from_fn(|| transfers.next_event()).last()
+    }
+
+    #[test]
+    fn resume_from_part_file() {
+        let mut transfers = transfers("resume");
+        let dir = transfers.config.download_dir.clone();
+        let (peer_id, data) = (PeerId::random(), vec![7; 1000]);
+
+        fs::create_dir_all(&dir).unwrap();
+        let part_path = dir.join(format!("{}-{peer_id}.part", hex(&sha256(&data))));
+        fs::write(&part_path, &data[..400]).unwrap();
+        let offset = transfers
+            .accept(peer_id, 0, "file.txt".into(), 1000, sha256(&data))
+            .unwrap();
+        assert_eq!(offset, 400);
+
+        transfers.write_chunk(peer_id, 0, &data[400..]).unwrap();
+        transfers.request_next(&mut behaviour(), peer_id, 0);
+        let Some(TransferEvent::Completed { path, .. }) = last_event(&mut transfers) else {
+            panic!("transfer should be completed");
+        };
+        assert_eq!(path, dir.join("file.txt"));
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!part_path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_mismatch_deletes_part_file() {
+        let mut transfers = transfers("mismatch");
+        let dir = transfers.config.download_dir.clone();
+        let peer_id = PeerId::random();
+
+        transfers
+            .accept(peer_id, 0, "file.txt".into(), 3, sha256(b"abc"))
+            .unwrap();
+        transfers.write_chunk(peer_id, 0, b"abd").unwrap();
+        transfers.request_next(&mut behaviour(), peer_id, 0);
+        assert!(matches!(
+            last_event(&mut transfers),
+            Some(TransferEvent::Failed { reason, .. }) if reason == "hash mismatch"
+        ));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_offers() {
+        let mut transfers = transfers("refuse");
+        let mut behaviour = behaviour();
+        let peer_id = PeerId::random();
+        let offer = |size| FileRequest::Offer {
+            id: 0,
+            name: "file.txt".into(),
+            size,
+            sha256: sha256(b""),
+        };
+
+        let response = transfers.respond(&mut behaviour, peer_id, true, offer(1001));
+        assert!(matches!(response, FileResponse::Rejected { .. }));
+        let response = transfers.respond(&mut behaviour, peer_id, false, offer(10));
+        assert!(matches!(response, FileResponse::Rejected { .. }));
+        assert!(!transfers.config.download_dir.exists());
+
+        // one transfer per partial file
+        let response = transfers.respond(&mut behaviour, peer_id, true, offer(1000));
+        assert!(matches!(response, FileResponse::Accepted { offset: 0 }));
+        let response = transfers.respond(&mut behaviour, peer_id, true, offer(1000));
+        assert!(matches!(response, FileResponse::Rejected { .. }));
+        fs::remove_dir_all(&transfers.config.download_dir).unwrap();
+    }
+
+    #[test]
+    fn complete_keeps_existing_files() {
+        let transfers = transfers("complete");
+        let dir = &transfers.config.download_dir;
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("file.txt"), b"old").unwrap();
+
+        let part_path = dir.join("new.part");
+        fs::write(&part_path, b"new").unwrap();
+        let path = transfers.complete(&part_path, "file.txt").unwrap();
+        assert_eq!(path, dir.join("file (1).txt"));
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(dir.join("file.txt")).unwrap(), b"old");
+        assert!(!part_path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prune_idle_offers() {
+        let mut transfers = transfers("prune");
+        transfers.config.idle_timeout = Duration::ZERO;
+        let path = std::env::temp_dir().join(format!("transfer-offer-{}", std::process::id()));
+        fs::write(&path, b"data").unwrap();
+
+        let peer_id = PeerId::random();
+        let id = transfers.offer(&mut behaviour(), peer_id, &path).unwrap();
+        transfers.prune();
+        assert!(transfers.outgoing.is_empty());
+        assert!(matches!(
+            last_event(&mut transfers),
+            Some(TransferEvent::Failed { id: failed, .. }) if failed == id
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
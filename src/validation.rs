@@ -0,0 +1,62 @@
+//! Application-level validation of received messages.
+//!
+//! Gossipsub only checks the signatures; whether the content of a message is acceptable
+//! is decided by a [`MessageValidator`], and the result is reported back to gossipsub.
+//! Rejected messages are not forwarded, and they count against the score of the peer that sent them.
+use libp2p::{PeerId, gossipsub::MessageAcceptance};
+
+/// Outcome of validating a message, see [`MessageValidator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationResult {
+    /// Message is delivered, and forwarded to other peers.
+    Accept,
+    /// Message is dropped, and penalizes the peer that propagated it.
+    Reject,
+    /// Message is dropped without any penalty.
+    Ignore,
+}
+
+impl From<ValidationResult> for MessageAcceptance {
+    fn from(result: ValidationResult) -> Self {
+        match result {
+            ValidationResult::Accept => MessageAcceptance::Accept,
+            ValidationResult::Reject => MessageAcceptance::Reject,
+            ValidationResult::Ignore => MessageAcceptance::Ignore,
+        }
+    }
+}
+
+/// Decides whether a received message is delivered and forwarded to other peers.
+///
+/// It is called once per payload, after its chunks are reassembled and decompressed.
+/// Malformed messages are rejected before they reach the validator.
+///
+/// Validators are shared by the worker threads, so they are called concurrently for different
+/// authors; state must be kept behind interior mutability. Messages of the same author are
+/// validated one at a time, in the order they are received. A validator that panics ignores
+/// the message.
+///
+/// Closures of the form `Fn(&PeerId, &[u8]) -> ValidationResult` are validators as well.
+pub trait MessageValidator: Send + Sync + 'static {
+    /// Validates the payload published by `author`.
+    fn validate(&self, author: &PeerId, payload: &[u8]) -> ValidationResult;
+}
+
+impl<F> MessageValidator for F
+where
+    F: Fn(&PeerId, &[u8]) -> ValidationResult + Send + Sync + 'static,
+{
+    fn validate(&self, author: &PeerId, payload: &[u8]) -> ValidationResult {
+        self(author, payload)
+    }
+}
+
+/// A validator that accepts every message, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AcceptAll;
+
+impl MessageValidator for AcceptAll {
+    fn validate(&self, _: &PeerId, _: &[u8]) -> ValidationResult {
+        ValidationResult::Accept
+    }
+}
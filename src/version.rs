@@ -0,0 +1,151 @@
+//! Protocol version compatibility between peers.
+//!
+//! Peers advertise `chat/{major}.{minor}` over identify. Following semver rules, peers are
+//! compatible when their major versions match, and they speak the lower of their minor versions.
+use crate::ChatBehaviour;
+use std::fmt;
+
+/// A version of the chat protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    pub major: u64,
+    pub minor: u64,
+}
+
+/// Reason for a peer to be incompatible with us.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum VersionMismatch {
+    #[error("Unknown protocol: {0}")]
+    UnknownProtocol(String),
+    #[error("Invalid version: {0}")]
+    InvalidVersion(String),
+    #[error("Major version {remote} is incompatible with ours ({local})")]
+    MajorVersion { local: u64, remote: u64 },
+}
+
+/// A peer that has been disconnected due to its protocol version.
+#[derive(Debug, Clone)]
+pub struct IncompatiblePeer {
+    /// Protocol version advertised by the peer.
+    pub protocol_version: String,
+    /// Why the version is incompatible.
+    pub reason: VersionMismatch,
+}
+
+impl ProtocolVersion {
+    /// Prefix of the protocol string, followed by the version.
+    pub const PREFIX: &str = "chat/";
+
+    /// Version of this node, see [`ChatBehaviour::PROTOCOL_VERSION`].
+    pub fn local() -> Self {
+        Self::parse(ChatBehaviour::PROTOCOL_VERSION).expect("should be valid")
+    }
+
+    /// Parses a protocol string such as `chat/1.2`; a patch version, if any, is ignored.
+    ///
+    /// Each part must be made of digits only, e.g. `chat/1.2.3` is valid but `chat/1.2-rc` isn't.
+    pub fn parse(protocol_version: &str) -> Result<Self, VersionMismatch> {
+        let version = protocol_version
+            .strip_prefix(Self::PREFIX)
+            .ok_or_else(|| VersionMismatch::UnknownProtocol(protocol_version.to_string()))?;
+
+        let invalid = || VersionMismatch::InvalidVersion(version.to_string());
+        // `u64::from_str` accepts a leading `+`, so the digits are checked first
+        let number = |part: &str| {
+            let digits = !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+            digits.then(|| part.parse().ok()).flatten()
+        };
+        let parts = version.split('.').collect::<Vec<_>>();
+        let (major, minor, patch) = match parts.as_slice() {
+            [major, minor] => (major, minor, None),
+            [major, minor, patch] => (major, minor, Some(patch)),
+            _ => return Err(invalid()),
+        };
+        if patch.is_some_and(|patch| number(patch).is_none()) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            major: number(major).ok_or_else(invalid)?,
+            minor: number(minor).ok_or_else(invalid)?,
+        })
+    }
+
+    /// Returns the version to speak with a remote peer, if it is compatible with ours.
+    pub fn negotiate(&self, remote: &Self) -> Result<Self, VersionMismatch> {
+        if self.major != remote.major {
+            return Err(VersionMismatch::MajorVersion {
+                local: self.major,
+                remote: remote.major,
+            });
+        }
+
+        Ok(Self {
+            major: self.major,
+            minor: self.minor.min(remote.minor),
+        })
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}.{}", Self::PREFIX, self.major, self.minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u64, minor: u64) -> ProtocolVersion {
+        ProtocolVersion { major, minor }
+    }
+
+    #[test]
+    fn parse_valid() {
+        assert_eq!(ProtocolVersion::parse("chat/1.2"), Ok(version(1, 2)));
+        assert_eq!(ProtocolVersion::parse("chat/0.10.3"), Ok(version(0, 10)));
+        assert_eq!(version(1, 2).to_string(), "chat/1.2");
+        assert!(ProtocolVersion::parse(ChatBehaviour::PROTOCOL_VERSION).is_ok());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(
+            ProtocolVersion::parse("ipfs/1.0"),
+            Err(VersionMismatch::UnknownProtocol("ipfs/1.0".into()))
+        );
+        for invalid in [
+            "1",
+            "1.",
+            ".1",
+            "1.x",
+            "0.1garbage",
+            "1.2.",
+            "1.2.x",
+            "1.2.3.4",
+            "+1.2",
+            "1.+2",
+        ] {
+            assert_eq!(
+                ProtocolVersion::parse(&format!("chat/{invalid}")),
+                Err(VersionMismatch::InvalidVersion(invalid.into())),
+                "{invalid} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        let local = version(1, 3);
+        assert_eq!(local.negotiate(&version(1, 1)), Ok(version(1, 1)));
+        assert_eq!(local.negotiate(&version(1, 5)), Ok(version(1, 3)));
+        assert_eq!(
+            local.negotiate(&version(0, 1)),
+            Err(VersionMismatch::MajorVersion {
+                local: 1,
+                remote: 0
+            })
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! A pool of worker threads that process the received messages off the swarm loop.
+//!
+//! Decompressing and validating a message can take a while, and the swarm can't make progress
+//! in the meantime (see the memory backpressure section of `docs/caveats.typ`). Instead, the
+//! swarm loop hands complete payloads to the workers over bounded queues, and the workers send
+//! the outcome back so that it can be reported to gossipsub.
+//!
+//! Each worker has a queue of its own, and the messages of an author always go to the same one,
+//! so that they are processed in the order they are received.
+use crate::message::Delivery;
+use crate::metrics::RejectReason;
+use crate::{ChatMessage, Compression, MessageValidator, ValidationResult};
+use libp2p::{PeerId, gossipsub::TopicHash};
+use prometheus_client::metrics::{counter::Counter, gauge::Gauge};
+use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::SystemTime;
+use tokio::sync::{Notify, mpsc};
+
+/// The validator, shared by all workers.
+///
+/// Workers clone the inner `Arc` before validating, so the lock is only held to replace it.
+pub(crate) type SharedValidator = Arc<RwLock<Arc<dyn MessageValidator>>>;
+
+/// What to do with a new item when a bounded queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest item in the queue to make room for the new one.
+    DropOldest,
+    /// Drop the new item.
+    DropNewest,
+    /// Wait until there is room, which stops the swarm loop in the meantime.
+    Block,
+}
+
+/// Configuration of the worker pool.
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Number of worker threads.
+    pub workers: usize,
+    /// Maximum number of messages waiting for a worker, split evenly among the workers.
+    pub queue_capacity: usize,
+    /// What to do with a received message when the queue is full.
+    ///
+    /// Dropped messages are reported as ignored to gossipsub, so their propagators are not penalized.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            queue_capacity: 1024,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+/// Current state of the worker pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Messages waiting for a worker.
+    pub queue_depth: usize,
+    /// Messages dropped because the queue was full.
+    pub dropped: u64,
+}
+
+/// A complete payload to be processed by a worker.
+pub(crate) struct Job {
+    pub(crate) author: PeerId,
+    pub(crate) topic: TopicHash,
+    pub(crate) timestamp: u64,
+    /// When the payload was complete, before it waited for a worker.
+    pub(crate) received_at: SystemTime,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) deliveries: Vec<Delivery>,
+}
+
+/// Outcome of processing a [`Job`].
+pub(crate) enum Outcome {
+    Accepted(ChatMessage),
+    Rejected(RejectReason),
+    Ignored,
+}
+
+/// A processed [`Job`], sent back to the swarm loop.
+pub(crate) struct Processed {
+    pub(crate) author: PeerId,
+    pub(crate) deliveries: Vec<Delivery>,
+    pub(crate) outcome: Outcome,
+}
+
+/// The bounded queue between the swarm loop and a worker.
+#[derive(Default)]
+struct Shard {
+    jobs: Mutex<VecDeque<Job>>,
+    /// Signaled to the worker when a job is added, or the queue is closed.
+    available: Condvar,
+    /// Signaled to the swarm loop when a job is taken.
+    space: Notify,
+}
+
+impl Shard {
+    /// Nothing panics while the jobs are locked, so a poisoned lock is still consistent.
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Job>> {
+        self.jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The queues of all workers.
+struct Queue {
+    shards: Vec<Shard>,
+    /// Capacity of each shard.
+    capacity: usize,
+    closed: AtomicBool,
+    depth: Gauge,
+    dropped: Counter,
+}
+
+pub(crate) struct WorkerPool {
+    queue: Arc<Queue>,
+    overflow: OverflowPolicy,
+}
+
+impl WorkerPool {
+    /// Starts the worker threads, which send the processed jobs to `results`.
+    pub(crate) fn new(
+        config: &WorkerConfig,
+        validator: SharedValidator,
+        max_message_size: usize,
+        results: mpsc::UnboundedSender<Processed>,
+        depth: Gauge,
+        dropped: Counter,
+    ) -> io::Result<Self> {
+        let workers = config.workers.max(1);
+        let queue = Arc::new(Queue {
+            shards: (0..workers).map(|_| Shard::default()).collect(),
+            capacity: config.queue_capacity.div_ceil(workers).max(1),
+            closed: AtomicBool::new(false),
+            depth,
+            dropped,
+        });
+
+        for i in 0..workers {
+            let queue = queue.clone();
+            let validator = validator.clone();
+            let results = results.clone();
+            std::thread::Builder::new()
+                .name(format!("chat-worker-{i}"))
+                .spawn(move || {
+                    while let Some(job) = queue.pop(i) {
+                        let processed = process(job, &validator, max_message_size);
+                        if results.send(processed).is_err() {
+                            break; // client is gone
+                        }
+                    }
+                })?;
+        }
+
+        Ok(Self {
+            queue,
+            overflow: config.overflow,
+        })
+    }
+
+    /// Adds a job to the queue of its author, and returns the job that is dropped as per the
+    /// overflow policy.
+    pub(crate) async fn submit(&self, job: Job) -> Option<Job> {
+        let queue = &self.queue;
+        let shard = queue.shard(&job.author);
+        loop {
+            // registered before checking, so that a job taken in between is not missed
+            let space = shard.space.notified();
+            {
+                let mut jobs = shard.lock();
+                if queue.closed.load(Ordering::Relaxed) {
+                    return Some(job);
+                }
+                if jobs.len() < queue.capacity {
+                    jobs.push_back(job);
+                    queue.depth.inc();
+                    shard.available.notify_one();
+                    return None;
+                }
+
+                match self.overflow {
+                    OverflowPolicy::DropOldest => {
+                        let oldest = jobs.pop_front();
+                        jobs.push_back(job);
+                        shard.available.notify_one();
+                        queue.dropped.inc();
+                        return oldest;
+                    }
+                    OverflowPolicy::DropNewest => {
+                        queue.dropped.inc();
+                        return Some(job);
+                    }
+                    OverflowPolicy::Block => {}
+                }
+            }
+
+            log::debug!("Worker queue is full, waiting for room");
+            space.await;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            queue_depth: self
+                .queue
+                .shards
+                .iter()
+                .map(|shard| shard.lock().len())
+                .sum(),
+            dropped: self.queue.dropped.get(),
+        }
+    }
+
+    /// Stops the workers once they are done with their current job, and returns the queued jobs.
+    pub(crate) fn close(&self) -> Vec<Job> {
+        self.queue.closed.store(true, Ordering::Relaxed);
+        let mut queued = Vec::new();
+        for shard in &self.queue.shards {
+            // taken so that the worker can't miss the notification between its check and its wait
+            queued.extend(shard.lock().drain(..));
+            shard.available.notify_all();
+            shard.space.notify_waiters();
+        }
+        self.queue.depth.set(0);
+        queued
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Queue {
+    /// Returns the shard of the author, which is always the same.
+    fn shard(&self, author: &PeerId) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        author.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Waits for the next job of the given shard, returns `None` once the queue is closed.
+    fn pop(&self, index: usize) -> Option<Job> {
+        let shard = &self.shards[index];
+        let mut jobs = shard.lock();
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            if let Some(job) = jobs.pop_front() {
+                self.depth.dec();
+                shard.space.notify_one();
+                return Some(job);
+            }
+            jobs = shard
+                .available
+                .wait(jobs)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Decompresses & validates the payload.
+///
+/// A panic (e.g. in the validator) ignores the message, and leaves the worker running.
+fn process(job: Job, validator: &SharedValidator, max_message_size: usize) -> Processed {
+    let Job {
+        author,
+        topic,
+        timestamp,
+        received_at,
+        compression,
+        payload,
+        deliveries,
+    } = job;
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        // decompress up to the maximum message size
+        let payload = match compression {
+            None => payload,
+            Some(compression) => match compression.decompress(&payload, max_message_size) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    log::warn!("Invalid message from {author}: {err}");
+                    return Outcome::Rejected(RejectReason::Decompression);
+                }
+            },
+        };
+
+        // let the application decide, and only then forward the message
+        let validator = validator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        match validator.validate(&author, &payload) {
+            ValidationResult::Accept => {
+                let data = String::from_utf8_lossy(&payload).into_owned();
+                let message = ChatMessage::new(author, topic, data, timestamp, received_at);
+                Outcome::Accepted(message)
+            }
+            ValidationResult::Reject => Outcome::Rejected(RejectReason::Validator),
+            ValidationResult::Ignore => Outcome::Ignored,
+        }
+    }))
+    .unwrap_or_else(|_| {
+        log::error!("Processing a message from {author} panicked, ignoring it");
+        Outcome::Ignored
+    });
+
+    Processed {
+        author,
+        deliveries,
+        outcome,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AcceptAll;
+    use std::collections::HashMap;
+    use std::sync::mpsc as std_mpsc;
+    use std::time::Duration;
+
+    fn job(author: PeerId, payload: &[u8]) -> Job {
+        Job {
+            author,
+            topic: TopicHash::from_raw("test"),
+            timestamp: 0,
+            received_at: SystemTime::now(),
+            compression: None,
+            payload: payload.to_vec(),
+            deliveries: Vec::new(),
+        }
+    }
+
+    fn pool(
+        workers: usize,
+        queue_capacity: usize,
+        overflow: OverflowPolicy,
+        validator: impl MessageValidator,
+    ) -> (WorkerPool, mpsc::UnboundedReceiver<Processed>) {
+        let config = WorkerConfig {
+            workers,
+            queue_capacity,
+            overflow,
+        };
+        let validator: SharedValidator = Arc::new(RwLock::new(Arc::new(validator)));
+        let (results, processed) = mpsc::unbounded_channel();
+        let pool = WorkerPool::new(
+            &config,
+            validator,
+            1024,
+            results,
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+        (pool, processed)
+    }
+
+    /// A validator that lets the test know when it starts, and waits for the test to let it finish.
+    fn gated() -> (
+        impl MessageValidator,
+        std_mpsc::Receiver<()>,
+        std_mpsc::SyncSender<()>,
+    ) {
+        let (started, on_start) = std_mpsc::sync_channel(16);
+        let (release, gate) = std_mpsc::sync_channel(16);
+        let (started, gate) = (Mutex::new(started), Mutex::new(gate));
+        let validator = move |_: &PeerId, _: &[u8]| {
+            let _ = started.lock().unwrap().send(());
+            let _ = gate.lock().unwrap().recv();
+            ValidationResult::Accept
+        };
+        (validator, on_start, release)
+    }
+
+    fn accepted(processed: Processed) -> Option<String> {
+        match processed.outcome {
+            Outcome::Accepted(message) => Some(message.data),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_order_per_author() {
+        let (pool, mut processed) = pool(4, 64, OverflowPolicy::Block, AcceptAll);
+        let authors = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for i in 0..10 {
+            for author in authors {
+                assert!(
+                    pool.submit(job(author, i.to_string().as_bytes()))
+                        .await
+                        .is_none()
+                );
+            }
+        }
+
+        let mut received = HashMap::<PeerId, Vec<String>>::new();
+        for _ in 0..30 {
+            let processed = processed.recv().await.unwrap();
+            let author = processed.author;
+            received
+                .entry(author)
+                .or_default()
+                .push(accepted(processed).unwrap());
+        }
+        let expected = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+        for author in authors {
+            assert_eq!(received[&author], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let author = PeerId::random();
+        for overflow in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let (validator, on_start, release) = gated();
+            let (pool, _processed) = pool(1, 1, overflow, validator);
+            assert!(pool.submit(job(author, b"busy")).await.is_none());
+            on_start.recv().unwrap();
+            assert!(pool.submit(job(author, b"queued")).await.is_none());
+
+            let dropped = pool.submit(job(author, b"new")).await.unwrap();
+            let expected: &[u8] = match overflow {
+                OverflowPolicy::DropOldest => b"queued",
+                _ => b"new",
+            };
+            assert_eq!(dropped.payload, expected);
+            assert_eq!(
+                pool.stats(),
+                WorkerStats {
+                    queue_depth: 1,
+                    dropped: 1
+                }
+            );
+            release.send(()).unwrap();
+        }
+
+        // blocks until the worker takes a job
+        let (validator, on_start, release) = gated();
+        let (pool, _processed) = pool(1, 1, OverflowPolicy::Block, validator);
+        assert!(pool.submit(job(author, b"busy")).await.is_none());
+        on_start.recv().unwrap();
+        assert!(pool.submit(job(author, b"queued")).await.is_none());
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(50), pool.submit(job(author, b"new")));
+        assert!(blocked.await.is_err());
+        release.send(()).unwrap();
+        assert!(pool.submit(job(author, b"new")).await.is_none());
+        release.send(()).unwrap();
+        assert_eq!(pool.stats().dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn survives_validator_panic() {
+        let validator = |_: &PeerId, payload: &[u8]| {
+            assert_ne!(payload, b"panic");
+            ValidationResult::Accept
+        };
+        let (pool, mut processed) = pool(1, 8, OverflowPolicy::Block, validator);
+        let author = PeerId::random();
+        pool.submit(job(author, b"panic")).await;
+        pool.submit(job(author, b"fine")).await;
+
+        assert!(matches!(
+            processed.recv().await.unwrap().outcome,
+            Outcome::Ignored
+        ));
+        assert_eq!(
+            accepted(processed.recv().await.unwrap()).as_deref(),
+            Some("fine")
+        );
+    }
+
+    #[tokio::test]
+    async fn close_returns_queued_jobs() {
+        let (validator, on_start, release) = gated();
+        let (pool, _processed) = pool(1, 8, OverflowPolicy::Block, validator);
+        let author = PeerId::random();
+        pool.submit(job(author, b"busy")).await;
+        on_start.recv().unwrap();
+        pool.submit(job(author, b"queued")).await;
+
+        let queued = pool.close();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].payload, b"queued");
+        assert_eq!(pool.stats().queue_depth, 0);
+
+        // jobs submitted once closed are given back
+        let rejected = pool.submit(job(author, b"late")).await.unwrap();
+        assert_eq!(rejected.payload, b"late");
+        release.send(()).unwrap();
+    }
+}